@@ -0,0 +1,283 @@
+use crate::repo::sha256_file;
+use crate::state::Installed;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where the copy of a config file of `name` as it was shipped is kept, to
+/// merge local edits with a new version.
+fn shipped_path(root_path: &str, name: &str, file: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/config/{}/{}", root_path, name, file))
+}
+
+fn new_path(root_path: &str, file: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}.sps-new", root_path, file))
+}
+
+fn keep_shipped(root_path: &str, name: &str, file: &str, from: &Path) {
+    let to = shipped_path(root_path, name, file);
+    std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+    std::fs::copy(from, &to).unwrap();
+}
+
+/// Whether the installed config `file` differs from the shipped version
+/// with digest `shipped`.
+pub fn is_modified(root_path: &str, file: &str, shipped: &str) -> bool {
+    let path = PathBuf::from(format!("{}/{}", root_path, file));
+    path.is_file() && sha256_file(&path) != shipped
+}
+
+/// Install the config files of `name` from `dest_path`, except the ones
+/// edited since the `old` shipped versions, or that were there before the
+/// package. Their new version is put next to them as `<file>.sps-new`.
+/// Returns the digests to record and the files that were held back.
+pub fn place(
+    root_path: &str,
+    name: &str,
+    dest_path: &Path,
+    config: &[String],
+    old: &BTreeMap<String, String>,
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut digests = BTreeMap::new();
+    let mut held = Vec::new();
+    for file in config.iter() {
+        let mut from = dest_path.to_path_buf();
+        from.push(file);
+        let shipped = sha256_file(&from);
+        let path = PathBuf::from(format!("{}/{}", root_path, file));
+        let edited = path.is_file()
+            && sha256_file(&path) != shipped
+            && old.get(file).is_none_or(|d| is_modified(root_path, file, d));
+        if edited {
+            std::fs::copy(&from, new_path(root_path, file)).unwrap();
+            println!(
+                "Kept your {}, the new version is in {}.sps-new. See sps config-diff and sps config-merge.",
+                file, file
+            );
+            // The digest stays that of the version the edits were made to.
+            match old.get(file) {
+                Some(digest) => {
+                    digests.insert(file.clone(), digest.clone());
+                }
+                None => {
+                    digests.insert(file.clone(), shipped);
+                    keep_shipped(root_path, name, file, &from);
+                }
+            }
+            held.push(file.clone());
+        } else {
+            let _ = std::fs::remove_file(new_path(root_path, file));
+            digests.insert(file.clone(), shipped);
+            keep_shipped(root_path, name, file, &from);
+        }
+    }
+    (digests, held)
+}
+
+/// Forget the shipped config files of a removed package. Edited ones stay
+/// installed.
+pub fn forget(root_path: &str, name: &str, config: &BTreeMap<String, String>) {
+    for (file, _) in config.iter() {
+        let _ = std::fs::remove_file(new_path(root_path, file));
+    }
+    let path = PathBuf::from(format!("{}/usr/sps/config/{}", root_path, name));
+    if path.is_dir() {
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// The config files with a new version waiting in `<file>.sps-new`, with
+/// the package they belong to.
+pub fn pending(root_path: &str, installed: &Installed) -> Vec<(String, String)> {
+    let mut pending = Vec::new();
+    for (name, p) in installed.packages.iter() {
+        for file in p.config.keys() {
+            if new_path(root_path, file).is_file() {
+                pending.push((name.clone(), file.clone()));
+            }
+        }
+    }
+    pending
+}
+
+/// Show how the new version of each pending config file differs from the
+/// installed one.
+pub fn diff(root_path: &str, installed: &Installed, package: Option<&str>) {
+    let pending: Vec<_> = pending(root_path, installed)
+        .into_iter()
+        .filter(|(name, _)| package.is_none_or(|p| p == name))
+        .collect();
+    if pending.is_empty() {
+        println!("No config files have a new version waiting.");
+    }
+    for (_, file) in pending.iter() {
+        // diff exits with 1 when the files differ, which they do.
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "diff -u {}/{} {}",
+                root_path,
+                file,
+                new_path(root_path, file).to_str().unwrap()
+            ))
+            .spawn()
+            .expect("failed to execute process")
+            .wait()
+            .unwrap();
+    }
+}
+
+/// How to settle a config file with a new version waiting.
+pub enum Merge {
+    /// Merge the changes between the shipped versions into the edited file.
+    ThreeWay,
+    TakeNew,
+    KeepLocal,
+}
+
+/// Settle the new version of config `file` waiting in `<file>.sps-new`.
+pub fn merge(root_path: &str, installed: &mut Installed, file: &str, how: Merge) {
+    let file = file.trim_start_matches('/');
+    let (name, p) = installed
+        .packages
+        .iter_mut()
+        .find(|(_, p)| p.config.contains_key(file))
+        .unwrap_or_else(|| panic!("{} is not a config file of an installed package", file));
+    let path = PathBuf::from(format!("{}/{}", root_path, file));
+    let new = new_path(root_path, file);
+    assert!(new.is_file(), "{} has no new version waiting", file);
+
+    match how {
+        Merge::ThreeWay => {
+            let shipped = shipped_path(root_path, name, file);
+            let base = if shipped.is_file() {
+                shipped.to_str().unwrap().to_string()
+            } else {
+                "/dev/null".to_string()
+            };
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    "diff3 -m {} {} {}",
+                    path.to_str().unwrap(),
+                    base,
+                    new.to_str().unwrap()
+                ))
+                .output()
+                .expect("failed to execute process");
+            assert!(
+                output.status.success(),
+                "{} does not merge cleanly. Edit it by hand and pass --keep-local, or pass --take-new to replace your edits.",
+                file
+            );
+            std::fs::write(&path, &output.stdout).unwrap();
+            println!("Merged the new version into {}.", file);
+        }
+        Merge::TakeNew => {
+            std::fs::copy(&new, &path).unwrap();
+            println!("Replaced {} with the new version.", file);
+        }
+        Merge::KeepLocal => println!("Kept {} as it is.", file),
+    }
+    // The new version is what the file is based on from now on.
+    p.config.insert(file.to_string(), sha256_file(&new));
+    keep_shipped(root_path, name, file, &new);
+    std::fs::remove_file(&new).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const CONF: &str = "etc/app.conf";
+
+    /// A root under `dir`, with the package to place staged next to it.
+    fn root(dir: &TestDir) -> String {
+        dir.write("root/etc/.keep", "");
+        dir.path().join("root").to_str().unwrap().to_string()
+    }
+
+    /// Stage `contents` as the config file the package ships, returning its
+    /// digest.
+    fn ship(dir: &TestDir, contents: &str) -> String {
+        dir.write(&format!("dest/{}", CONF), contents);
+        sha256_file(&dir.path().join("dest").join(CONF))
+    }
+
+    fn place_conf(
+        dir: &TestDir,
+        old: &BTreeMap<String, String>,
+    ) -> (BTreeMap<String, String>, Vec<String>) {
+        let dest = dir.path().join("dest");
+        place(&root(dir), "app", &dest, &[CONF.to_string()], old)
+    }
+
+    /// Write the installed config file, returning its digest.
+    fn edit(dir: &TestDir, contents: &str) -> String {
+        dir.write(&format!("root/{}", CONF), contents);
+        sha256_file(&dir.path().join("root").join(CONF))
+    }
+
+    fn digests(digest: &str) -> BTreeMap<String, String> {
+        let mut digests = BTreeMap::new();
+        digests.insert(CONF.to_string(), digest.to_string());
+        digests
+    }
+
+    #[test]
+    fn a_new_file_is_placed() {
+        let dir = TestDir::new();
+        let shipped = ship(&dir, "a = 1\n");
+        let (recorded, held) = place_conf(&dir, &BTreeMap::new());
+        assert!(held.is_empty());
+        assert_eq!(recorded, digests(&shipped));
+        assert!(shipped_path(&root(&dir), "app", CONF).is_file());
+    }
+
+    #[test]
+    fn an_unedited_file_is_replaced() {
+        let dir = TestDir::new();
+        let before = edit(&dir, "a = 1\n");
+        dir.write(&format!("root/{}.sps-new", CONF), "stale");
+        let shipped = ship(&dir, "a = 2\n");
+        let (recorded, held) = place_conf(&dir, &digests(&before));
+        assert!(held.is_empty());
+        assert_eq!(recorded, digests(&shipped));
+        assert!(!new_path(&root(&dir), CONF).exists());
+    }
+
+    #[test]
+    fn an_edited_file_is_kept() {
+        let dir = TestDir::new();
+        let before = edit(&dir, "a = 1\n");
+        edit(&dir, "a = 1\nmine = true\n");
+        ship(&dir, "a = 2\n");
+        let (recorded, held) = place_conf(&dir, &digests(&before));
+        assert_eq!(held, [CONF]);
+        // Still based on the version the edits were made to.
+        assert_eq!(recorded, digests(&before));
+        let new = std::fs::read_to_string(new_path(&root(&dir), CONF)).unwrap();
+        assert_eq!(new, "a = 2\n");
+    }
+
+    #[test]
+    fn a_file_that_was_there_before_is_kept() {
+        let dir = TestDir::new();
+        edit(&dir, "local = true\n");
+        let shipped = ship(&dir, "a = 1\n");
+        let (recorded, held) = place_conf(&dir, &BTreeMap::new());
+        assert_eq!(held, [CONF]);
+        assert_eq!(recorded, digests(&shipped));
+        assert!(new_path(&root(&dir), CONF).is_file());
+    }
+
+    #[test]
+    fn an_edit_matching_the_new_version_is_not_held() {
+        let dir = TestDir::new();
+        edit(&dir, "a = 2\n");
+        let shipped = ship(&dir, "a = 2\n");
+        let (recorded, held) = place_conf(&dir, &digests("edited from something else"));
+        assert!(held.is_empty());
+        assert_eq!(recorded, digests(&shipped));
+    }
+}
@@ -0,0 +1,485 @@
+use semver::{Version, VersionReq};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The newest index format this version of sps understands.
+pub const MANIFEST_FORMAT: u32 = 1;
+
+/// `manifest.toml` at the top of an index, written on every push.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    /// Goes up by one on every push, so an older index can be told apart
+    /// from a newer one.
+    pub generation: u64,
+    /// When the index was pushed, in seconds since the unix epoch.
+    #[serde(default)]
+    pub published: u64,
+    /// When clients should stop trusting the index unless it has been
+    /// pushed again by then, in seconds since the unix epoch.
+    pub expires: Option<u64>,
+    /// CID of the whole `pkgs` directory, absent while it is empty.
+    pub pkgs: Option<String>,
+    /// sha256 of the files next to `pkgs` the signature should cover too,
+    /// meta.toml and maintainers.toml, by name.
+    #[serde(default)]
+    pub digests: BTreeMap<String, String>,
+    /// CID of every `pkgs/<name>/<major>/<version>` directory, by name and
+    /// version. Clients check them when there is no CID for `pkgs`.
+    #[serde(default)]
+    pub packages: BTreeMap<String, BTreeMap<String, String>>,
+    /// Keys allowed to sign the index besides the repository's own, by
+    /// maintainer name. Only older clients go by this, newer ones trust the
+    /// maintainers.toml signed by the repository's key.
+    #[serde(default)]
+    pub maintainers: BTreeMap<String, String>,
+}
+
+/// The files next to `pkgs` whose digests go in the manifest.
+pub const DIGESTED: [&str; 2] = ["meta.toml", "maintainers.toml"];
+
+/// The maintainers whose keys may sign the index, kept in maintainers.toml
+/// in the index and signed by the repository's key in
+/// maintainers.signature.toml.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Maintainers {
+    /// Goes up by one on every change, so an older list listing a revoked
+    /// maintainer can be refused.
+    #[serde(default)]
+    pub serial: u64,
+    #[serde(default)]
+    pub maintainers: BTreeMap<String, String>,
+}
+
+pub fn read_maintainers(index_path: &Path) -> Maintainers {
+    let mut path = index_path.to_path_buf();
+    path.push("maintainers.toml");
+    std::fs::read_to_string(&path)
+        .map(|s| toml::from_str(&s).expect("maintainers.toml is corrupt"))
+        .unwrap_or_default()
+}
+
+/// The maintainers of the index at `index_path`, if their list is signed by
+/// `repo_key`, checking signatures with `verify`. An index without a list
+/// has none.
+pub fn signed_maintainers(
+    index_path: &Path,
+    repo_key: &str,
+    verify: &dyn Fn(&Signature, &Path) -> bool,
+) -> Result<Maintainers, String> {
+    let mut path = index_path.to_path_buf();
+    path.push("maintainers.toml");
+    if !path.exists() {
+        return Ok(Maintainers::default());
+    }
+    let maintainers: Maintainers = toml::from_str(&std::fs::read_to_string(&path).unwrap())
+        .map_err(|e| format!("maintainers.toml is corrupt: {}", e))?;
+    match read_signature_file(index_path, "maintainers.signature.toml") {
+        Some(signature) if signature.key == repo_key && verify(&signature, &path) => {
+            Ok(maintainers)
+        }
+        _ => Err(format!("maintainers.toml is not signed by {}", repo_key)),
+    }
+}
+
+/// Check the files the manifest has digests of are the ones in the index,
+/// using `sha256` to hash them. Manifests written before digests existed
+/// have none. `meta_name` is where the meta.toml that was signed is kept.
+pub fn check_digests(
+    index_path: &Path,
+    manifest: &Manifest,
+    meta_name: &str,
+    sha256: &dyn Fn(&Path) -> String,
+) -> Result<(), String> {
+    if manifest.digests.is_empty() {
+        return Ok(());
+    }
+    for name in DIGESTED.iter() {
+        let mut path = index_path.to_path_buf();
+        path.push(if *name == "meta.toml" { meta_name } else { name });
+        match (manifest.digests.get(*name), path.exists()) {
+            (Some(digest), true) if *digest == sha256(&path) => (),
+            (None, false) => (),
+            _ => return Err(format!("{} does not match the manifest", name)),
+        }
+    }
+    Ok(())
+}
+
+/// `signature.toml`, signing the `manifest.toml` of an index or the
+/// `moved.toml` of a pointer next to it, or `maintainers.signature.toml`
+/// signing `maintainers.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Signature {
+    /// Id of the signing key, in the same form as repository addresses.
+    pub key: String,
+    pub signature: String,
+}
+
+pub fn read_signature(dir_path: &Path) -> Option<Signature> {
+    read_signature_file(dir_path, "signature.toml")
+}
+
+pub fn read_signature_file(dir_path: &Path, file_name: &str) -> Option<Signature> {
+    let mut path = dir_path.to_path_buf();
+    path.push(file_name);
+    std::fs::read_to_string(&path).ok().map(|s| {
+        toml::from_str(&s).unwrap_or_else(|e| panic!("{:?} is corrupt: {}", path, e))
+    })
+}
+
+/// `moved.toml`, published in place of the index at the old address of a
+/// repository whose key was rotated.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Moved {
+    pub from: String,
+    pub to: String,
+}
+
+/// The manifest of an index, if it has one. Indexes pushed before
+/// manifests existed do not.
+pub fn read_manifest(index_path: &Path) -> Option<Manifest> {
+    let mut path = index_path.to_path_buf();
+    path.push("manifest.toml");
+    std::fs::read_to_string(&path).ok().map(|s| {
+        toml::from_str(&s).unwrap_or_else(|e| panic!("{:?} is corrupt: {}", path, e))
+    })
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Every version and revision found under `pkgs/<name>/<major>/` of an
+/// index, keyed by package name and sorted oldest first.
+pub fn package_versions(index_path: &Path) -> BTreeMap<String, Vec<(Version, u64)>> {
+    let mut packages = BTreeMap::new();
+    let mut pkgs_path = index_path.to_path_buf();
+    pkgs_path.push("pkgs");
+    if !pkgs_path.is_dir() {
+        return packages;
+    }
+    for pkg in std::fs::read_dir(&pkgs_path).unwrap() {
+        let pkg = pkg.unwrap();
+        if !pkg.path().is_dir() {
+            continue;
+        }
+        let name = pkg.file_name().to_str().unwrap().to_string();
+        packages.insert(name.clone(), revisions_of(index_path, &name));
+    }
+    packages
+}
+
+/// Split the name of a `pkgs/<name>/<major>/<dir>` directory into version
+/// and revision. Rebuilds of a version are published as `<version>_<revision>`.
+pub fn parse_version_dir(dir: &str) -> Option<(Version, u64)> {
+    match dir.rfind('_') {
+        Some(at) => Some((Version::parse(&dir[..at]).ok()?, dir[at + 1..].parse().ok()?)),
+        None => Some((Version::parse(dir).ok()?, 0)),
+    }
+}
+
+/// A version as shown to users and accepted by install, like 1.2.0 or
+/// 1.2.0_1 for its first rebuild. It is also the name of its directory, as
+/// `_` cannot occur in a semver version, so 1.2.0-1, a pre-release, stays
+/// apart from it.
+pub fn display_version(version: &Version, revision: u64) -> String {
+    version_dir(version, revision)
+}
+
+/// Read a version the way display_version shows it.
+pub fn parse_display_version(version: &str) -> Option<(Version, u64)> {
+    parse_version_dir(version)
+}
+
+pub fn version_dir(version: &Version, revision: u64) -> String {
+    if revision == 0 {
+        format!("{}", version)
+    } else {
+        format!("{}_{}", version, revision)
+    }
+}
+
+/// Every version of `name` in an index and its revisions, oldest first.
+pub fn revisions_of(index_path: &Path, name: &str) -> Vec<(Version, u64)> {
+    let mut versions = Vec::new();
+    let mut pkg_path = index_path.to_path_buf();
+    pkg_path.push("pkgs");
+    pkg_path.push(name);
+    if !pkg_path.is_dir() {
+        return versions;
+    }
+    for major in std::fs::read_dir(&pkg_path).unwrap() {
+        let major = major.unwrap();
+        if !major.path().is_dir() {
+            continue;
+        }
+        for version in std::fs::read_dir(major.path()).unwrap() {
+            let version = version.unwrap();
+            if let Some(v) = parse_version_dir(version.file_name().to_str().unwrap()) {
+                versions.push(v);
+            }
+        }
+    }
+    versions.sort();
+    versions
+}
+
+/// The newest revision of `version` of `name` in an index.
+pub fn newest_revision(index_path: &Path, name: &str, version: &Version) -> Option<u64> {
+    revisions_of(index_path, name)
+        .into_iter()
+        .filter(|(v, _)| v == version)
+        .map(|(_, r)| r)
+        .next_back()
+}
+
+/// Versions present in `new` that were not in `old`, per package.
+pub fn new_versions(
+    old: &BTreeMap<String, Vec<(Version, u64)>>,
+    new: &BTreeMap<String, Vec<(Version, u64)>>,
+) -> BTreeMap<String, Vec<(Version, u64)>> {
+    let mut added = BTreeMap::new();
+    for (name, versions) in new.iter() {
+        let known = old.get(name);
+        let fresh: Vec<(Version, u64)> = versions
+            .iter()
+            .filter(|v| !matches!(known, Some(k) if k.contains(v)))
+            .cloned()
+            .collect();
+        if !fresh.is_empty() {
+            added.insert(name.clone(), fresh);
+        }
+    }
+    added
+}
+
+/// `providers.toml` in `pkgs/<name>/` of an index, listing the packages
+/// that provide the virtual package `name`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Providers {
+    #[serde(default)]
+    pub providers: Vec<String>,
+}
+
+pub fn read_providers(index_path: &Path, name: &str) -> Providers {
+    let mut path = index_path.to_path_buf();
+    path.push("pkgs");
+    path.push(name);
+    path.push("providers.toml");
+    match std::fs::read_to_string(&path) {
+        Ok(s) => toml::from_str(&s).unwrap_or_else(|e| panic!("{:?} is corrupt: {}", path, e)),
+        Err(_) => Providers::default(),
+    }
+}
+
+/// Whether `name` is only a virtual package in all of `repos`, with
+/// providers but no versions of its own.
+pub fn is_virtual(repos: &[(String, PathBuf)], name: &str) -> bool {
+    repos
+        .iter()
+        .all(|(_, index_path)| revisions_of(index_path, name).is_empty())
+        && repos
+            .iter()
+            .any(|(_, index_path)| !read_providers(index_path, name).providers.is_empty())
+}
+
+/// The added repositories as `(hash, index path)`, highest priority first.
+pub fn repos_by_priority(root_path: &str) -> Vec<(String, PathBuf)> {
+    let repos_path = PathBuf::from(format!("{}/usr/sps/repos", root_path));
+    let mut priority_path = repos_path.clone();
+    priority_path.push("priority");
+    let priorities = std::fs::read_to_string(&priority_path)
+        .unwrap_or_default()
+        .parse::<toml::Value>()
+        .expect("usr/sps/repos/priority is not valid toml");
+    let mut repos: Vec<(i64, String)> = priorities
+        .as_table()
+        .unwrap()
+        .iter()
+        .map(|(hash, priority)| {
+            (
+                priority
+                    .as_integer()
+                    .unwrap_or_else(|| panic!("The priority of {} must be a number", hash)),
+                hash.clone(),
+            )
+        })
+        .collect();
+    repos.sort_by_key(|r| std::cmp::Reverse(r.0));
+    repos
+        .into_iter()
+        .map(|(_, hash)| {
+            let mut index_path = repos_path.clone();
+            index_path.push(&hash);
+            (hash, index_path)
+        })
+        .collect()
+}
+
+/// A single version of a package as published in a repository index.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub name: String,
+    pub version: Version,
+    /// Packaging revision, counting rebuilds of the same version.
+    pub revision: u64,
+    pub repo: String,
+    /// The `pkgs/<name>/<major>/<version>[_<revision>]` directory in the
+    /// index.
+    pub path: PathBuf,
+    pub dependencies: Vec<(String, VersionReq)>,
+    /// Virtual packages it provides, like sh.
+    pub provides: Vec<String>,
+    /// Why the version was yanked, if it was.
+    pub yanked: Option<String>,
+    pub deprecated: Option<String>,
+}
+
+pub fn read_candidate(
+    repo: &str,
+    index_path: &Path,
+    name: &str,
+    version: &Version,
+    revision: u64,
+) -> Candidate {
+    let mut path = index_path.to_path_buf();
+    path.push("pkgs");
+    path.push(name);
+    path.push(format!("{}", version.major));
+    path.push(version_dir(version, revision));
+    path.push("meta.toml");
+    let meta = std::fs::read_to_string(&path)
+        .unwrap_or_else(|_| {
+            panic!(
+                "{} {} has no meta.toml in {}",
+                name,
+                display_version(version, revision),
+                repo
+            )
+        })
+        .parse::<toml::Value>()
+        .unwrap();
+    path.pop();
+    Candidate {
+        name: name.to_string(),
+        version: version.clone(),
+        revision,
+        repo: repo.to_string(),
+        path,
+        dependencies: crate::repo::read_dependencies(&meta),
+        provides: crate::repo::read_provides(&meta),
+        yanked: meta
+            .get("yanked")
+            .map(|r| r.as_str().unwrap_or_default().to_string()),
+        deprecated: meta
+            .get("deprecated")
+            .map(|r| r.as_str().unwrap_or_default().to_string()),
+    }
+}
+
+/// The newest version and revision of `name` that `accept` allows from a
+/// repository, from the highest priority repository that has one. Yanked
+/// versions are skipped, except for the `installed` one.
+pub fn newest(
+    repos: &[(String, PathBuf)],
+    name: &str,
+    installed: Option<(&Version, u64)>,
+    accept: impl Fn(&str, &Version, u64) -> bool,
+) -> Option<Candidate> {
+    for (repo, index_path) in repos.iter() {
+        let mut versions = revisions_of(index_path, name);
+        versions.retain(|(v, r)| accept(repo, v, *r));
+        for (version, revision) in versions.iter().rev() {
+            let candidate = read_candidate(repo, index_path, name, version, *revision);
+            if candidate.yanked.is_none() || Some((version, *revision)) == installed {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn version_dirs_carry_the_revision() {
+        assert_eq!(parse_version_dir("1.2.0"), Some((v("1.2.0"), 0)));
+        assert_eq!(parse_version_dir("1.2.0_3"), Some((v("1.2.0"), 3)));
+        assert_eq!(parse_version_dir("1.2.0-rc1_2"), Some((v("1.2.0-rc1"), 2)));
+        assert_eq!(
+            parse_version_dir("1.2.0+build_1"),
+            Some((v("1.2.0+build"), 1))
+        );
+        assert_eq!(parse_version_dir("1.2.0_x"), None);
+        assert_eq!(parse_version_dir("providers.toml"), None);
+    }
+
+    #[test]
+    fn version_dirs_round_trip() {
+        for (version, revision) in [("1.2.0", 0), ("1.2.0", 1), ("1.2.0-rc.1", 4)].iter() {
+            let dir = version_dir(&v(version), *revision);
+            assert_eq!(parse_version_dir(&dir), Some((v(version), *revision)));
+        }
+    }
+
+    #[test]
+    fn display_versions_show_the_revision_after_an_underscore() {
+        assert_eq!(display_version(&v("1.2.0"), 0), "1.2.0");
+        assert_eq!(display_version(&v("1.2.0"), 1), "1.2.0_1");
+        assert_eq!(display_version(&v("1.2.0-rc1"), 2), "1.2.0-rc1_2");
+    }
+
+    #[test]
+    fn numeric_pre_releases_are_not_revisions() {
+        let pre = display_version(&v("1.0.0-1"), 0);
+        let rebuild = display_version(&v("1.0.0"), 1);
+        assert_ne!(pre, rebuild);
+        assert_eq!(parse_display_version(&pre), Some((v("1.0.0-1"), 0)));
+        assert_eq!(parse_display_version(&rebuild), Some((v("1.0.0"), 1)));
+    }
+
+    #[test]
+    fn revisions_order_after_their_version() {
+        let mut versions = vec![
+            (v("1.2.0"), 1),
+            (v("1.10.0"), 0),
+            (v("1.2.0"), 0),
+            (v("1.2.0-rc1"), 3),
+        ];
+        versions.sort();
+        assert_eq!(
+            versions,
+            [
+                (v("1.2.0-rc1"), 3),
+                (v("1.2.0"), 0),
+                (v("1.2.0"), 1),
+                (v("1.10.0"), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn display_versions_parse_back() {
+        assert_eq!(parse_display_version("1.2.0_1"), Some((v("1.2.0"), 1)));
+        assert_eq!(
+            parse_display_version("1.2.0-rc1_2"),
+            Some((v("1.2.0-rc1"), 2))
+        );
+        assert_eq!(
+            parse_display_version("1.2.0-rc1"),
+            Some((v("1.2.0-rc1"), 0))
+        );
+        assert_eq!(parse_display_version("1.2"), None);
+    }
+}
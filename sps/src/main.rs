@@ -1,4 +1,11 @@
+mod config_files;
+mod index;
 mod repo;
+mod resolve;
+mod state;
+#[cfg(test)]
+mod test_dir;
+mod transaction;
 use repo::*;
 
 use clap::Clap;
@@ -22,12 +29,141 @@ enum SubCommand {
     )]
     Repository(Repository),
     Add_Repo(Add_Repo),
+    Update(Update),
+    Install(Install),
+    Remove(Remove),
+    Upgrade(Upgrade),
+    Pin(Pin),
+    Unpin(Unpin),
+    Hold(Hold),
+    Unhold(Unhold),
+    Pins(Pins),
+    Channel(Channel),
+    Alternatives(Alternatives),
+    ConfigDiff(ConfigDiff),
+    ConfigMerge(ConfigMerge),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Clap)]
 struct Add_Repo {
     repo_hash: String,
+    /// The repository is a mirror of this address. Its index may then be
+    /// signed by that repository.
+    #[clap(long)]
+    mirror_of: Option<String>,
+}
+
+/// Refresh the index of every added repository.
+#[derive(Clap)]
+struct Update {}
+
+/// Install packages, given as name or name@version-requirement.
+#[derive(Clap)]
+struct Install {
+    /// Allow pre-releases of every package, whatever channel its
+    /// repository follows.
+    #[clap(long)]
+    pre: bool,
+    #[clap(required = true)]
+    packages: Vec<String>,
+}
+
+/// Remove installed packages.
+#[derive(Clap)]
+struct Remove {
+    #[clap(required = true)]
+    packages: Vec<String>,
+}
+
+/// Upgrade installed packages to the newest available versions.
+#[derive(Clap)]
+struct Upgrade {
+    /// Upgrade every installed package.
+    #[clap(long)]
+    all: bool,
+    /// Allow upgrading to a new major version.
+    #[clap(long)]
+    major: bool,
+    /// Allow pre-releases of every package, whatever channel its
+    /// repository follows.
+    #[clap(long)]
+    pre: bool,
+    /// Only show what would be done.
+    #[clap(long)]
+    dry_run: bool,
+    packages: Vec<String>,
+}
+
+/// Only allow versions of a package matching a requirement.
+#[derive(Clap)]
+struct Pin {
+    name: String,
+    version_req: String,
+}
+
+/// Remove the pin on a package.
+#[derive(Clap)]
+struct Unpin {
+    name: String,
+}
+
+/// Keep a package at its installed version.
+#[derive(Clap)]
+struct Hold {
+    name: String,
+}
+
+/// Let a held package change version again.
+#[derive(Clap)]
+struct Unhold {
+    name: String,
+}
+
+/// List pinned and held packages.
+#[derive(Clap)]
+struct Pins {}
+
+/// List the providers of virtual packages, or switch a virtual package to
+/// another provider.
+#[derive(Clap)]
+struct Alternatives {
+    /// Only list the providers of this virtual package.
+    name: Option<String>,
+    /// Install this provider in place of the installed one and prefer it
+    /// from now on.
+    provider: Option<String>,
+}
+
+/// Show how the new versions of edited config files, waiting in
+/// <file>.sps-new, differ from the installed ones.
+#[derive(Clap)]
+struct ConfigDiff {
+    /// Only show the config files of this package.
+    package: Option<String>,
+}
+
+/// Settle an edited config file with a new version waiting in
+/// <file>.sps-new, by merging the changes between the shipped versions
+/// into it.
+#[derive(Clap)]
+struct ConfigMerge {
+    /// The config file, like etc/hello.conf.
+    file: String,
+    /// Replace the edited file with the new version instead.
+    #[clap(long)]
+    take_new: bool,
+    /// Keep the edited file as it is instead.
+    #[clap(long)]
+    keep_local: bool,
+}
+
+/// Show or set the channel followed for a repository: stable, beta or
+/// nightly. Stable repositories only provide releases.
+#[derive(Clap)]
+struct Channel {
+    repo_hash: String,
+    channel: Option<state::Channel>,
 }
 
 fn main() {
@@ -44,41 +180,810 @@ fn main() {
             let mut current_path =
                 PathBuf::from(format!("{}/usr/sps/repos", &root_path));
             create_dir_all(&current_path).unwrap();
-            ipfs_get(&current_path,
-                &format!("/ipns/{}", &a.repo_hash), "new_repo");
-            
-            let mut second_path = current_path.clone();
-            second_path.push(&a.repo_hash);
-            current_path.push("new_repo");
-            
-            //un_tar(&current_path);
-            
-            //current_path.pop();
-            //current_path.push("index");
-            current_path.push("meta.toml");
-            let repo_meta : RepoMetaData =
-                toml::from_str(&read_to_string(&current_path).unwrap()).unwrap();
-            current_path.pop();
-            
-            second_path.pop();
-            second_path.push(&a.repo_hash);
-            let new = !second_path.exists();
-            if !new { // delete the old index
-                remove_dir_all(&second_path).unwrap();
-            }
-            rename(&current_path, &second_path).unwrap(); //index to hash
-            
+            let _lock = lock_repos(&current_path);
+
+            let mirror_of = a
+                .mirror_of
+                .clone()
+                .or_else(|| state::Mirrors::load(&current_path).repos.remove(&a.repo_hash));
+            let (repo_hash, _) =
+                refresh_repo(&root_path, &current_path, &a.repo_hash, mirror_of.as_deref());
+            // Only a mirror whose index checked out is remembered as one.
+            if let Some(upstream) = a.mirror_of {
+                let mut mirrors = state::Mirrors::load(&current_path);
+                mirrors.repos.insert(repo_hash.clone(), upstream);
+                mirrors.save(&current_path);
+            }
+            let new = !index::repos_by_priority(&root_path)
+                .iter()
+                .any(|(hash, _)| *hash == repo_hash);
+
             if new { use std::io::Write;
             const default_priority : usize = 10;
-            current_path.pop(); current_path.push("priority");
+            current_path.push("priority");
             let mut priority_file = 
         OpenOptions::new().create(true).append(true).open(&current_path).unwrap();
             priority_file.write_all(
-                format!("{} = {}\n", a.repo_hash, default_priority).as_bytes()).unwrap();
+                format!("{} = {}\n", repo_hash, default_priority).as_bytes()).unwrap();
             }        
         }
+        SubCommand::Update(_) => {
+            update_repos(&root_path);
+        }
+        SubCommand::Install(i) => {
+            let targets: Vec<_> = i.packages.iter().map(|p| resolve::parse_request(p)).collect();
+            run_transaction(&root_path, &targets, &[], false, i.pre, false, None);
+        }
+        SubCommand::Remove(r) => {
+            run_transaction(&root_path, &[], &r.packages, false, false, false, None);
+        }
+        SubCommand::Upgrade(u) => {
+            assert!(
+                u.all == u.packages.is_empty(),
+                "Pass either --all or the names of the packages to upgrade"
+            );
+            let installed = state::Installed::load(&root_path);
+            let pins = state::Pins::load(&root_path);
+            let names = if u.all {
+                let (held, names): (Vec<String>, Vec<String>) = installed
+                    .packages
+                    .keys()
+                    .cloned()
+                    .partition(|n| pins.holds.contains(n));
+                for name in held {
+                    println!("Not upgrading {}, it is held.", name);
+                }
+                let repos = index::repos_by_priority(&root_path);
+                let (names, gone): (Vec<String>, Vec<String>) =
+                    names.into_iter().partition(|name| {
+                        repos
+                            .iter()
+                            .any(|(_, index_path)| !index::revisions_of(index_path, name).is_empty())
+                    });
+                for name in gone {
+                    eprintln!(
+                        "warning: not upgrading {}, no repository has it any more.",
+                        name
+                    );
+                }
+                names
+            } else {
+                u.packages.clone()
+            };
+            for name in names.iter() {
+                assert!(installed.packages.contains_key(name), "{} is not installed", name);
+                assert!(
+                    !pins.holds.contains(name),
+                    "{} is held at {}, run sps unhold {} to upgrade it",
+                    name,
+                    installed.packages[name].version,
+                    name
+                );
+            }
+            let targets: Vec<_> = names
+                .into_iter()
+                .map(|name| resolve::Request {
+                    name,
+                    req: semver::VersionReq::any(),
+                    revision: None,
+                })
+                .collect();
+            run_transaction(&root_path, &targets, &[], u.major, u.pre, u.dry_run, None);
+        }
+        SubCommand::Pin(p) => {
+            let req = semver::VersionReq::parse(&p.version_req)
+                .unwrap_or_else(|e| panic!("Invalid version requirement {}: {}", p.version_req, e));
+            let _lock = state::lock(&root_path);
+            let mut pins = state::Pins::load(&root_path);
+            if let Some(installed) = state::Installed::load(&root_path).packages.get(&p.name) {
+                if !req.matches(&installed.version) {
+                    println!(
+                        "{} {} is installed, which does not match {}. It will change on the next upgrade.",
+                        p.name, installed.version, req
+                    );
+                }
+            }
+            pins.pins.insert(p.name, req);
+            pins.save(&root_path);
+        }
+        SubCommand::Unpin(u) => {
+            let _lock = state::lock(&root_path);
+            let mut pins = state::Pins::load(&root_path);
+            assert!(pins.pins.remove(&u.name).is_some(), "{} is not pinned", u.name);
+            pins.save(&root_path);
+        }
+        SubCommand::Hold(h) => {
+            let _lock = state::lock(&root_path);
+            let mut pins = state::Pins::load(&root_path);
+            assert!(
+                state::Installed::load(&root_path).packages.contains_key(&h.name),
+                "{} is not installed",
+                h.name
+            );
+            pins.holds.insert(h.name);
+            pins.save(&root_path);
+        }
+        SubCommand::Unhold(u) => {
+            let _lock = state::lock(&root_path);
+            let mut pins = state::Pins::load(&root_path);
+            assert!(pins.holds.remove(&u.name), "{} is not held", u.name);
+            pins.save(&root_path);
+        }
+        SubCommand::Pins(_) => {
+            let pins = state::Pins::load(&root_path);
+            let installed = state::Installed::load(&root_path);
+            for (name, req) in pins.pins.iter() {
+                println!("{} pinned to {}", name, req);
+            }
+            for name in pins.holds.iter() {
+                match installed.packages.get(name) {
+                    Some(p) => println!("{} held at {}", name, p.version),
+                    None => println!("{} held, not installed", name),
+                }
+            }
+        }
+        SubCommand::ConfigDiff(d) => {
+            let installed = state::Installed::load(&root_path);
+            config_files::diff(&root_path, &installed, d.package.as_deref());
+        }
+        SubCommand::ConfigMerge(m) => {
+            let how = match (m.take_new, m.keep_local) {
+                (false, false) => config_files::Merge::ThreeWay,
+                (true, false) => config_files::Merge::TakeNew,
+                (false, true) => config_files::Merge::KeepLocal,
+                (true, true) => panic!("Pass either --take-new or --keep-local"),
+            };
+            let _lock = state::lock(&root_path);
+            let mut installed = state::Installed::load(&root_path);
+            config_files::merge(&root_path, &mut installed, &m.file, how);
+            installed.save(&root_path);
+        }
+        SubCommand::Alternatives(a) => {
+            let repos = index::repos_by_priority(&root_path);
+            let installed = state::Installed::load(&root_path);
+            let alternatives = state::Alternatives::load(&root_path);
+            // Every virtual package with its providers, highest priority first.
+            let mut virtuals: std::collections::BTreeMap<String, Vec<String>> =
+                std::collections::BTreeMap::new();
+            for (_, index_path) in repos.iter() {
+                for name in index::package_versions(index_path).keys() {
+                    let providers = virtuals.entry(name.clone()).or_default();
+                    for provider in index::read_providers(index_path, name).providers {
+                        if !providers.contains(&provider) {
+                            providers.push(provider);
+                        }
+                    }
+                }
+            }
+            virtuals.retain(|_, providers| !providers.is_empty());
+            let name = match a.name {
+                Some(name) => name,
+                None => {
+                    for name in virtuals.keys() {
+                        print_alternatives(name, &virtuals[name], &installed, &alternatives);
+                    }
+                    return;
+                }
+            };
+            let providers = virtuals
+                .get(&name)
+                .unwrap_or_else(|| panic!("No repository has a package providing {}", name));
+            let provider = match a.provider {
+                Some(provider) => provider,
+                None => {
+                    print_alternatives(&name, providers, &installed, &alternatives);
+                    return;
+                }
+            };
+            assert!(
+                providers.contains(&provider),
+                "{} does not provide {}, {} do",
+                provider,
+                name,
+                providers.join(", ")
+            );
+            let current: Vec<&String> = installed
+                .packages
+                .iter()
+                .filter(|(p, i)| **p != provider && i.provides.contains(&name))
+                .map(|(p, _)| p)
+                .collect();
+            if current.is_empty() {
+                let _lock = state::lock(&root_path);
+                let mut alternatives = state::Alternatives::load(&root_path);
+                alternatives.preferred.insert(name.clone(), provider.clone());
+                alternatives.save(&root_path);
+                println!("{} will provide {} from now on.", provider, name);
+                return;
+            }
+            // Removing the old provider makes whatever needs the virtual
+            // package pull in the preferred one. If it was installed on
+            // request, the new one takes its place as such.
+            let targets: Vec<_> = if current.iter().any(|p| installed.packages[*p].explicit) {
+                vec![resolve::parse_request(&provider)]
+            } else {
+                Vec::new()
+            };
+            let removals: Vec<String> = current.into_iter().cloned().collect();
+            run_transaction(
+                &root_path,
+                &targets,
+                &removals,
+                false,
+                false,
+                false,
+                Some((&name, &provider)),
+            );
+        }
+        SubCommand::Channel(c) => {
+            let repos_path = std::path::PathBuf::from(format!("{}/usr/sps/repos", root_path));
+            assert!(
+                index::repos_by_priority(&root_path)
+                    .iter()
+                    .any(|(hash, _)| *hash == c.repo_hash),
+                "{} has not been added",
+                c.repo_hash
+            );
+            match c.channel {
+                Some(channel) => {
+                    let _lock = lock_repos(&repos_path);
+                    let mut channels = state::Channels::load(&repos_path);
+                    if channel == state::Channel::Stable {
+                        channels.repos.remove(&c.repo_hash);
+                    } else {
+                        channels.repos.insert(c.repo_hash, channel);
+                    }
+                    channels.save(&repos_path);
+                }
+                None => println!("{}", state::Channels::load(&repos_path).get(&c.repo_hash)),
+            }
+        }
+    }
+}
+
+/// Resolve and carry out one transaction while holding the locks on the
+/// repository indexes and the installed packages. `prefer` sets the
+/// preferred provider of a virtual package, which is only saved once the
+/// transaction went through.
+fn run_transaction(
+    root_path: &str,
+    targets: &[resolve::Request],
+    removals: &[String],
+    allow_major: bool,
+    pre: bool,
+    dry_run: bool,
+    prefer: Option<(&str, &str)>,
+) {
+    let repos_path = std::path::PathBuf::from(format!("{}/usr/sps/repos", root_path));
+    std::fs::create_dir_all(&repos_path).unwrap();
+    let _repos_lock = lock_repos(&repos_path);
+    let _lock = state::lock(root_path);
+
+    let mut installed = state::Installed::load(root_path);
+    transaction::finish_interrupted(root_path, &mut installed);
+    let pins = state::Pins::load(root_path);
+    let mut alternatives = state::Alternatives::load(root_path);
+    if let Some((name, provider)) = prefer {
+        alternatives
+            .preferred
+            .insert(name.to_string(), provider.to_string());
+    }
+    resolve::warn_retracted(root_path, &installed);
+    let plan = resolve::resolve(
+        root_path,
+        &installed,
+        &pins,
+        &alternatives,
+        targets,
+        removals,
+        allow_major,
+        pre,
+    );
+    if plan.actions.is_empty() {
+        println!("Nothing to do.");
+    } else {
+        plan.print();
+    }
+    if !dry_run {
+        transaction::execute(root_path, &plan, &mut installed);
+        if prefer.is_some() {
+            alternatives.save(root_path);
+        }
+    }
+}
+
+/// List the providers of the virtual package `name`, noting which one is
+/// installed and which one is preferred.
+fn print_alternatives(
+    name: &str,
+    providers: &[String],
+    installed: &state::Installed,
+    alternatives: &state::Alternatives,
+) {
+    println!("{}", name);
+    for provider in providers.iter() {
+        let mut notes = String::new();
+        if installed
+            .packages
+            .get(provider)
+            .is_some_and(|p| p.provides.iter().any(|p| p == name))
+        {
+            notes.push_str(" (installed)");
+        }
+        if alternatives.preferred.get(name) == Some(provider) {
+            notes.push_str(" (preferred)");
+        }
+        println!("    {}{}", provider, notes);
+    }
+}
+
+fn update_repos(root_path: &str) {
+    use std::fs::*;
+    use std::path::*;
+    let repos_path = PathBuf::from(format!("{}/usr/sps/repos", root_path));
+    create_dir_all(&repos_path).unwrap();
+    let _lock = lock_repos(&repos_path);
+    let mut priority_path = repos_path.clone();
+    priority_path.push("priority");
+    let priorities = read_to_string(&priority_path)
+        .unwrap_or_default()
+        .parse::<toml::Value>()
+        .expect("usr/sps/repos/priority is not valid toml");
+
+    for repo_hash in priorities.as_table().unwrap().keys() {
+        let mut index_path = repos_path.clone();
+        index_path.push(repo_hash);
+        let old_versions = index::package_versions(&index_path);
+        let mirror_of = state::Mirrors::load(&repos_path).repos.remove(repo_hash);
+        let (repo_hash, updated) =
+            refresh_repo(root_path, &repos_path, repo_hash, mirror_of.as_deref());
+        if !updated {
+            println!("{} is up to date.", repo_hash);
+            continue;
+        }
+        index_path.pop();
+        index_path.push(&repo_hash);
+        let new_versions = index::package_versions(&index_path);
+
+        println!("Updated {}.", repo_hash);
+        for (name, versions) in index::new_versions(&old_versions, &new_versions) {
+            let versions: Vec<String> = versions
+                .iter()
+                .map(|(v, r)| index::display_version(v, *r))
+                .collect();
+            println!("    {} {}", name, versions.join(", "));
+        }
     }
 }
+
+/// Fetch the index `repo_hash` currently publishes, following it to a new
+/// address if its key was rotated. Returns the address the repository is at
+/// now and whether its index changed. `mirror_of` is the repository it is a
+/// mirror of, if it is one.
+/// The caller must hold the lock from `lock_repos`.
+fn refresh_repo(
+    root_path: &str,
+    repos_path: &std::path::Path,
+    repo_hash: &str,
+    mirror_of: Option<&str>,
+) -> (String, bool) {
+    let mut repo_hash = repo_hash.to_string();
+    loop {
+        let cid = ipfs_name_resolve(&format!("/ipns/{}", repo_hash));
+        let cid = cid.trim_start_matches("/ipfs/");
+        match fetch_repo_index(repos_path, &repo_hash, cid, mirror_of) {
+            Fetched::Unchanged => return (repo_hash, false),
+            Fetched::Updated => return (repo_hash, true),
+            Fetched::Moved(to) => {
+                println!("{} has moved to {}.", repo_hash, to);
+                move_repo(root_path, repos_path, &repo_hash, &to);
+                repo_hash = to;
+            }
+        }
+    }
+}
+
+/// Carry everything known about the repository at `from` over to `to`:
+/// its priority, the generation last seen, and the installed packages
+/// that came from it.
+fn move_repo(root_path: &str, repos_path: &std::path::Path, from: &str, to: &str) {
+    use std::fs::*;
+    let mut priority_path = repos_path.to_path_buf();
+    priority_path.push("priority");
+    let mut priorities = read_to_string(&priority_path)
+        .unwrap_or_default()
+        .parse::<toml::Value>()
+        .expect("usr/sps/repos/priority is not valid toml");
+    let table = priorities.as_table_mut().unwrap();
+    if let Some(priority) = table.remove(from) {
+        table.entry(to.to_string()).or_insert(priority);
+        write(&priority_path, toml::to_string(&priorities).unwrap()).unwrap();
+    }
+
+    let mut seen = state::SeenIndexes::load(repos_path);
+    if let Some(last_seen) = seen.repos.remove(from) {
+        seen.repos.entry(to.to_string()).or_insert(last_seen);
+        seen.save(repos_path);
+    }
+
+    let mut channels = state::Channels::load(repos_path);
+    if let Some(channel) = channels.repos.remove(from) {
+        channels.repos.entry(to.to_string()).or_insert(channel);
+        channels.save(repos_path);
+    }
+
+    let mut mirrors = state::Mirrors::load(repos_path);
+    if let Some(upstream) = mirrors.repos.remove(from) {
+        mirrors.repos.entry(to.to_string()).or_insert(upstream);
+        mirrors.save(repos_path);
+    }
+
+    let mut index_path = repos_path.to_path_buf();
+    index_path.push(from);
+    match read_link(&index_path) {
+        Ok(target) => {
+            remove_file(&index_path).unwrap();
+            let mut target_path = repos_path.to_path_buf();
+            target_path.push(target);
+            remove_dir_all(&target_path).unwrap();
+        }
+        Err(_) if index_path.is_dir() => remove_dir_all(&index_path).unwrap(),
+        Err(_) => (),
+    }
+
+    let _lock = state::lock(root_path);
+    let mut installed = state::Installed::load(root_path);
+    for p in installed.packages.values_mut().filter(|p| p.repo == from) {
+        p.repo = to.to_string();
+    }
+    installed.save(root_path);
+}
+
+/// Take the lock guarding `usr/sps/repos`. It is held until the returned
+/// file is dropped.
+fn lock_repos(repos_path: &std::path::Path) -> std::fs::File {
+    use fs2::FileExt;
+    let mut lock_path = repos_path.to_path_buf();
+    lock_path.push("lock");
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .unwrap();
+    lock_file.lock_exclusive().unwrap();
+    lock_file
+}
+
+enum Fetched {
+    /// `cid` already was the current index.
+    Unchanged,
+    Updated,
+    /// `cid` is a pointer to the new address of the repository.
+    Moved(String),
+}
+
+/// Fetch `/ipfs/<cid>` as the new index of `repo_hash` and swap it into
+/// place. The caller must hold the lock from `lock_repos`.
+fn fetch_repo_index(
+    repos_path: &std::path::Path,
+    repo_hash: &str,
+    cid: &str,
+    mirror_of: Option<&str>,
+) -> Fetched {
+    use std::fs::*;
+    use uuid::Uuid;
+    let mut index_path = repos_path.to_path_buf();
+    index_path.push(repo_hash);
+    let fetched_name = format!("{}.{}", repo_hash, cid);
+    if let Ok(target) = read_link(&index_path) {
+        if target.as_os_str() == fetched_name.as_str() {
+            // Being served the same index forever is how a repository gets
+            // frozen, so it has to still be within its validity window.
+            if let Some(expires) = index::read_manifest(&index_path).and_then(|m| m.expires) {
+                assert!(
+                    expires >= index::now(),
+                    "The index of {} expired {} hours ago and has not been republished since. Someone may be serving a frozen copy of it.",
+                    repo_hash,
+                    (index::now() - expires) / 3600
+                );
+            }
+            return Fetched::Unchanged;
+        }
+    }
+
+    // Fetch into a name no other run can be using, and only give it its
+    // final name once it is known to be a complete index.
+    let tmp_name = format!(
+        ".{}.{}.tmp",
+        repo_hash,
+        Uuid::new_v4()
+            .to_simple()
+            .encode_lower(&mut Uuid::encode_buffer())
+    );
+    ipfs_get(repos_path, &format!("/ipfs/{}", cid), &tmp_name);
+    let mut tmp_path = repos_path.to_path_buf();
+    tmp_path.push(&tmp_name);
+    let mut moved_path = tmp_path.clone();
+    moved_path.push("moved.toml");
+    if moved_path.exists() {
+        let moved = check_moved(repo_hash, &tmp_path);
+        remove_dir_all(&tmp_path).unwrap();
+        return match moved {
+            Ok(to) => Fetched::Moved(to),
+            Err(e) => panic!("{}", e),
+        };
+    }
+    let mut seen = state::SeenIndexes::load(repos_path);
+    let manifest = match check_index(
+        repo_hash,
+        &tmp_path,
+        seen.repos.get(repo_hash),
+        mirror_of,
+    ) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            remove_dir_all(&tmp_path).unwrap();
+            panic!("{}", e);
+        }
+    };
+
+    let mut fetched_path = repos_path.to_path_buf();
+    fetched_path.push(&fetched_name);
+    if fetched_path.exists() {
+        remove_dir_all(&fetched_path).unwrap();
+    }
+    rename(&tmp_path, &fetched_path).unwrap();
+    swap_repo_index(repos_path, repo_hash, &fetched_name);
+
+    if let Some((manifest, maintainers)) = manifest {
+        seen.repos.insert(
+            repo_hash.to_string(),
+            state::SeenIndex {
+                generation: manifest.generation,
+                published: manifest.published,
+                fetched: index::now(),
+                signed: index::read_signature(&fetched_path).is_some(),
+                maintainers_serial: maintainers.serial,
+                pkgs: manifest.pkgs,
+            },
+        );
+        seen.save(repos_path);
+    }
+    Fetched::Updated
+}
+
+/// The keys besides the repository's own that may sign its index: the
+/// maintainers its key signed the list of, and for a mirror the repository
+/// it was added as a mirror of. The mirror_of in the index's meta.toml is
+/// not signed, so it is not taken from there.
+fn signers(maintainers: &index::Maintainers, mirror_of: Option<&str>) -> Vec<String> {
+    maintainers
+        .maintainers
+        .values()
+        .cloned()
+        .chain(mirror_of.map(|m| m.to_string()))
+        .collect()
+}
+
+/// Check the pointer published at `repo_hash` after its key was rotated is
+/// signed by that key, and return the address it points to.
+fn check_moved(repo_hash: &str, pointer_path: &std::path::Path) -> Result<String, String> {
+    let mut moved_path = pointer_path.to_path_buf();
+    moved_path.push("moved.toml");
+    let moved: index::Moved = toml::from_str(&std::fs::read_to_string(&moved_path).unwrap())
+        .map_err(|e| format!("{} points to a new address with a bad moved.toml: {}", repo_hash, e))?;
+    let signed = index::read_signature(pointer_path)
+        .is_some_and(|s| s.key == repo_hash && ipfs_key_verify(&s, &moved_path));
+    if moved.from != repo_hash || !signed {
+        return Err(format!(
+            "{} points to {}, but the pointer is not signed by {}",
+            repo_hash, moved.to, repo_hash
+        ));
+    }
+    Ok(moved.to)
+}
+
+/// Check a freshly fetched index of `repo_hash` is complete, understood by
+/// this sps, and not older than the last one seen from the repository.
+/// Returns its manifest and the maintainers trusted to sign it.
+fn check_index(
+    repo_hash: &str,
+    index_path: &std::path::Path,
+    last_seen: Option<&state::SeenIndex>,
+    mirror_of: Option<&str>,
+) -> Result<Option<(index::Manifest, index::Maintainers)>, String> {
+    let mut meta_path = index_path.to_path_buf();
+    meta_path.push("meta.toml");
+    let meta = std::fs::read_to_string(&meta_path)
+        .map_err(|_| format!("The index published by {} has no meta.toml", repo_hash))?;
+    toml::from_str::<RepoMetaData>(&meta)
+        .map_err(|e| format!("The index published by {} has a bad meta.toml: {}", repo_hash, e))?;
+
+    let manifest = match index::read_manifest(index_path) {
+        Some(manifest) => manifest,
+        None => {
+            return match last_seen {
+                Some(seen) => Err(format!(
+                    "The index published by {} has no manifest, but generation {} was seen before. Refusing to roll back.",
+                    repo_hash, seen.generation
+                )),
+                None => Ok(None),
+            }
+        }
+    };
+    if manifest.format > index::MANIFEST_FORMAT {
+        return Err(format!(
+            "The index published by {} has format {}, this sps only understands up to {}. Upgrade sps.",
+            repo_hash,
+            manifest.format,
+            index::MANIFEST_FORMAT
+        ));
+    }
+    if let Some(seen) = last_seen {
+        if manifest.generation < seen.generation {
+            return Err(format!(
+                "The index published by {} is generation {}, older than generation {} seen before. Refusing to roll back.",
+                repo_hash, manifest.generation, seen.generation
+            ));
+        }
+        // Every push makes a new generation, so one seen before cannot have
+        // changed since.
+        if manifest.generation == seen.generation
+            && (manifest.published != seen.published
+                || seen.pkgs.is_some() && manifest.pkgs != seen.pkgs)
+        {
+            return Err(format!(
+                "The index published by {} is generation {} like the one seen before, but differs from it",
+                repo_hash, manifest.generation
+            ));
+        }
+    }
+    if let Some(expires) = manifest.expires {
+        if expires < index::now() {
+            return Err(format!(
+                "The index published by {} expired {} hours ago. Someone may be serving a stale copy of it.",
+                repo_hash,
+                (index::now() - expires) / 3600
+            ));
+        }
+    }
+    let mut maintainers = index::Maintainers::default();
+    match index::read_signature(index_path) {
+        Some(signature) => {
+            // Only maintainers the repository's own key vouches for may sign,
+            // as listed in this index, so a revoked one is refused right away.
+            maintainers = index::signed_maintainers(
+                index_path,
+                mirror_of.unwrap_or(repo_hash),
+                &ipfs_key_verify,
+            )
+            .map_err(|e| format!("The index published by {} is refused: {}", repo_hash, e))?;
+            if let Some(seen) = last_seen {
+                if maintainers.serial < seen.maintainers_serial {
+                    return Err(format!(
+                        "The index published by {} lists its maintainers as of change {}, older than change {} seen before. Refusing to roll back.",
+                        repo_hash, maintainers.serial, seen.maintainers_serial
+                    ));
+                }
+            }
+            let trusted = signers(&maintainers, mirror_of).contains(&signature.key);
+            if signature.key != repo_hash && !trusted {
+                return Err(format!(
+                    "The index published by {} is signed by {}, which is not one of its maintainers",
+                    repo_hash, signature.key
+                ));
+            }
+            let mut manifest_path = index_path.to_path_buf();
+            manifest_path.push("manifest.toml");
+            if !ipfs_key_verify(&signature, &manifest_path) {
+                return Err(format!(
+                    "The signature on the index published by {} is not valid",
+                    repo_hash
+                ));
+            }
+            let meta_name = if mirror_of.is_some() { "upstream.meta.toml" } else { "meta.toml" };
+            index::check_digests(index_path, &manifest, meta_name, &sha256_file)
+                .map_err(|e| format!("In the index published by {}, {}", repo_hash, e))?;
+        }
+        None if last_seen.is_some_and(|seen| seen.signed) => {
+            return Err(format!(
+                "The index published by {} is not signed, but earlier ones were",
+                repo_hash
+            ));
+        }
+        None => (),
+    }
+    let mut pkgs_path = index_path.to_path_buf();
+    pkgs_path.push("pkgs");
+    // The CID of pkgs covers every version in it, so the per-version CIDs
+    // only need checking without it.
+    match manifest.pkgs.as_ref() {
+        Some(pkgs) => {
+            if ipfs_add_only_hash(&pkgs_path) != *pkgs {
+                return Err(format!(
+                    "The packages in the index published by {} do not match its manifest",
+                    repo_hash
+                ));
+            }
+        }
+        None => check_version_cids(&pkgs_path, &manifest)
+            .map_err(|e| format!("In the index published by {}, {}", repo_hash, e))?,
+    }
+    Ok(Some((manifest, maintainers)))
+}
+
+/// Check the `pkgs/<name>/<major>/<version>` directories are exactly the
+/// ones the manifest lists, with the CIDs it gives for them.
+fn check_version_cids(pkgs_path: &std::path::Path, manifest: &index::Manifest) -> Result<(), String> {
+    let mut unlisted = Vec::new();
+    for (name, versions) in index::package_versions(pkgs_path.parent().unwrap()) {
+        for (version, revision) in versions {
+            let dir = index::version_dir(&version, revision);
+            if !manifest.packages.get(&name).is_some_and(|v| v.contains_key(&dir)) {
+                unlisted.push(format!("{} {}", name, index::display_version(&version, revision)));
+            }
+        }
+    }
+    if !unlisted.is_empty() {
+        return Err(format!("the manifest does not list {}", unlisted.join(", ")));
+    }
+    for (name, versions) in manifest.packages.iter() {
+        for (dir, cid) in versions.iter() {
+            let (version, revision) = index::parse_version_dir(dir)
+                .ok_or_else(|| format!("the manifest lists {} {}, which is not a version", name, dir))?;
+            let mut version_path = pkgs_path.to_path_buf();
+            version_path.push(name);
+            version_path.push(version.major.to_string());
+            version_path.push(dir);
+            if !version_path.is_dir() || ipfs_add_only_hash(&version_path) != *cid {
+                return Err(format!(
+                    "{} {} does not match its manifest",
+                    name,
+                    index::display_version(&version, revision)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Point `repos/<hash>` at the freshly fetched `repos/<fetched_name>`.
+/// `repos/<hash>` is a symlink that gets renamed over, so readers always see
+/// either the whole old index or the whole new one.
+fn swap_repo_index(repos_path: &std::path::Path, repo_hash: &str, fetched_name: &str) {
+    use std::fs::*;
+    let mut index_path = repos_path.to_path_buf();
+    index_path.push(repo_hash);
+    let mut link_path = repos_path.to_path_buf();
+    link_path.push(format!(".{}.link", repo_hash));
+    if symlink_metadata(&link_path).is_ok() {
+        remove_file(&link_path).unwrap();
+    }
+    std::os::unix::fs::symlink(fetched_name, &link_path).unwrap();
+
+    let old_target = read_link(&index_path).ok();
+    if old_target.is_none() && index_path.is_dir() {
+        // Indexes fetched before the symlink layout are plain directories,
+        // which a symlink cannot be renamed over.
+        let mut legacy_path = repos_path.to_path_buf();
+        legacy_path.push(format!("{}.legacy", repo_hash));
+        rename(&index_path, &legacy_path).unwrap();
+        rename(&link_path, &index_path).unwrap();
+        remove_dir_all(&legacy_path).unwrap();
+        return;
+    }
+    rename(&link_path, &index_path).unwrap();
+
+    if let Some(old_target) = old_target {
+        if old_target.as_os_str() != fetched_name {
+            let mut old_path = repos_path.to_path_buf();
+            old_path.push(old_target);
+            remove_dir_all(&old_path).unwrap();
+        }
+    }
+}
+
 fn un_tar(tar_path: &std::path::Path) {
 let mut output = std::process::Command::new("sh")
         .arg("-c")
@@ -110,6 +1015,58 @@ fn ipfs_get(output_dir: &std::path::Path, ipfs_address: &str,
             Or is there a file in the same directory by the same name as the hash?");
 }
 
+fn ipfs_name_resolve(ipns_address: &str) -> String {
+    let mut output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("ipfs name resolve {}", ipns_address))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success(), "could not resolve {}", ipns_address);
+    if !output.stdout.is_empty() {
+        output.stdout.truncate(output.stdout.len() - 1);
+    }
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Whether `signature` is a valid signature of the file at `path`.
+fn ipfs_key_verify(signature: &index::Signature, path: &std::path::Path) -> bool {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "ipfs key verify --enc=json --key={} --signature={} {}",
+            signature.key,
+            signature.signature,
+            path.to_str().unwrap()
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    output.status.success()
+        && serde_json::from_slice::<serde_json::Value>(&output.stdout)
+            .is_ok_and(|v| v["SignatureValid"] == serde_json::Value::Bool(true))
+}
+
+fn ipfs_add_only_hash(item_path: &std::path::Path) -> String {
+    let mut output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "ipfs add --cid-version 1 -rnQ {}",
+            item_path.to_str().unwrap()
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success());
+    if !output.stdout.is_empty() {
+        output.stdout.truncate(output.stdout.len() - 1);
+    }
+    String::from_utf8(output.stdout).unwrap()
+}
+
 fn ipfs_get_and_uncompress(output_dir: &std::path::Path, ipfs_address: &str,
         out_name: &str) {
     let mut output = std::process::Command::new("sh")
@@ -8,12 +8,39 @@ pub enum Repository {
     Push(Push),
     Daemon(Daemon),
     Delete(Delete),
+    Yank(Yank),
+    Deprecate(Deprecate),
+    RemovePackage(RemovePackage),
+    Gc(Gc),
+    List(List),
+    Status(Status),
+    Config(Config),
+    ImportKey(ImportKey),
+    Maintainer(Maintainer),
+    RotateKey(RotateKey),
+    Mirror(Mirror),
+    InitProject(InitProject),
+    LintProject(LintProject),
 }
 #[derive(Clap)]
 pub struct Add {
     path_to_repo: PathBuf,
+    /// A project directory, a git repository together with --rev, or a
+    /// source tarball.
     #[clap(default_value = ".")]
     path_to_proj: PathBuf,
+    /// Package this commit, branch or tag of the git repository instead of
+    /// its working tree.
+    #[clap(long)]
+    rev: Option<String>,
+    /// Publish a rebuild of a version that is already published, as this
+    /// packaging revision. Overrides `revision` in meta.toml.
+    #[clap(long)]
+    revision: Option<u64>,
+    /// Replace the version if it is already published. Clients that have
+    /// fetched it will see its artifacts change.
+    #[clap(long)]
+    force: bool,
 }
 #[derive(Clap)]
 pub struct New {
@@ -29,27 +56,223 @@ pub struct New {
 pub struct Push {
     // Repository to push
     path_to_repo: PathBuf,
+    /// Clients refuse the index if it has not been pushed again within
+    /// this many days. Defaults to valid_days in repo.toml.
+    #[clap(long)]
+    valid_days: Option<u64>,
 }
 #[derive(Clap)]
 pub struct Daemon {
     // Repository to start the daemon for.
     path_to_repo: PathBuf,
+    /// Without one, the daemon runs in the foreground.
+    #[clap(subcommand)]
+    action: Option<DaemonAction>,
+}
+#[derive(Clap)]
+pub enum DaemonAction {
+    /// Start the daemon in the background and wait until it is ready.
+    Start,
+    /// Stop a daemon started with start.
+    Stop,
+    /// Show whether the daemon is running.
+    Status,
 }
 #[derive(Clap)]
 pub struct Delete {
     // Repository to delete
     #[clap()]
     path_to_repo: PathBuf,
+    /// Do not ask before deleting.
+    #[clap(long)]
+    force: bool,
+    /// Save the repo's IPNS key to this file first, so its address can be
+    /// restored with import-key.
+    #[clap(long)]
+    export_key: Option<PathBuf>,
+}
+/// Manage the keys allowed to sign the index. The repo's own key always is.
+/// A maintainer's key only signs: the index is still pushed from this repo's
+/// node and published under the repo's key, with the maintainer's key file
+/// set as signing_key in its repo.toml. Revoking the key stops clients from
+/// accepting what it signs without moving the repo to a new address.
+#[derive(Clap)]
+pub struct Maintainer {
+    path_to_repo: PathBuf,
+    #[clap(subcommand)]
+    action: MaintainerAction,
+}
+#[derive(Clap)]
+pub enum MaintainerAction {
+    List,
+    /// Allow a key to sign the index.
+    Add(MaintainerAdd),
+    /// Stop allowing a maintainer's key to sign the index.
+    Revoke(MaintainerRevoke),
+    /// Create a signing key and save it to a file, for use as signing_key in
+    /// repo.toml.
+    NewKey(MaintainerNewKey),
+}
+#[derive(Clap)]
+pub struct MaintainerAdd {
+    name: String,
+    /// Id of the maintainer's signing key, as printed by new-key.
+    key: String,
+}
+#[derive(Clap)]
+pub struct MaintainerRevoke {
+    name: String,
+}
+#[derive(Clap)]
+pub struct MaintainerNewKey {
+    key_file: PathBuf,
+}
+/// Move the repo to a new address under a fresh key. The old address is
+/// left pointing at the new one, so clients follow along.
+#[derive(Clap)]
+pub struct RotateKey {
+    path_to_repo: PathBuf,
+}
+/// Create meta.toml, config.toml and sps_build.sh for a new package.
+#[derive(Clap)]
+pub struct InitProject {
+    #[clap(default_value = ".")]
+    path_to_proj: PathBuf,
+    /// Defaults to the name of the directory.
+    #[clap(long)]
+    name: Option<String>,
+    #[clap(long, default_value = "0.1.0")]
+    version: String,
+}
+/// Check a project can be added to a repo, without adding it.
+#[derive(Clap)]
+pub struct LintProject {
+    #[clap(default_value = ".")]
+    path_to_proj: PathBuf,
+}
+/// Copy a repo published by someone else into a local repo, created if it
+/// does not exist, and publish the copy under the local repo's key. Run it
+/// again to bring the copy up to date.
+#[derive(Clap)]
+pub struct Mirror {
+    /// Address of the repo to mirror.
+    upstream: String,
+    path_to_repo: PathBuf,
+    /// Keep going, syncing again every this many minutes.
+    #[clap(long)]
+    every: Option<u64>,
+    #[clap(short, default_value = "16461")]
+    port: u16,
+    #[clap(short, default_value = "16462")]
+    swarm_port: u16,
+}
+/// Make a repo publish under a key saved with delete --export-key, taking
+/// over the address of the repo it came from.
+#[derive(Clap)]
+pub struct ImportKey {
+    path_to_repo: PathBuf,
+    key_file: PathBuf,
+    /// Replace the key the repo publishes under now. Needs --export-old-key,
+    /// as nothing can be published to the old address without it.
+    #[clap(long)]
+    force: bool,
+    /// Save the key being replaced to this file first.
+    #[clap(long)]
+    export_old_key: Option<PathBuf>,
+}
+/// Retract a published version so it is no longer picked for new installs.
+#[derive(Clap)]
+pub struct Yank {
+    path_to_repo: PathBuf,
+    name: String,
+    version: String,
+    #[clap(long)]
+    reason: Option<String>,
+    /// Take back an earlier yank.
+    #[clap(long)]
+    undo: bool,
+}
+/// Mark a published version as deprecated. It can still be installed.
+#[derive(Clap)]
+pub struct Deprecate {
+    path_to_repo: PathBuf,
+    name: String,
+    version: String,
+    #[clap(long)]
+    reason: Option<String>,
+    /// Take back an earlier deprecation.
+    #[clap(long)]
+    undo: bool,
+}
+/// Remove a package, one major version of it, or a single version from
+/// the index.
+#[derive(Clap)]
+pub struct RemovePackage {
+    path_to_repo: PathBuf,
+    name: String,
+    /// A major version like 2, or a full version like 2.1.0 or 2.1.0-1.
+    version: Option<String>,
+}
+/// Unpin artifacts neither the index nor the last pushed index refers to.
+#[derive(Clap)]
+pub struct Gc {
+    path_to_repo: PathBuf,
+    /// Only list what would be unpinned.
+    #[clap(long)]
+    dry_run: bool,
+}
+/// List the packages, versions and variants in the index.
+#[derive(Clap)]
+pub struct List {
+    path_to_repo: PathBuf,
+}
+/// Show the repo's identity, what was last pushed and whether its daemon is up.
+#[derive(Clap)]
+pub struct Status {
+    path_to_repo: PathBuf,
+}
+/// Show or change the settings in repo.toml. Changes are applied to the
+/// repo's ipfs node right away.
+#[derive(Clap)]
+pub struct Config {
+    path_to_repo: PathBuf,
+    #[clap(subcommand)]
+    action: ConfigAction,
+}
+#[derive(Clap)]
+pub enum ConfigAction {
+    /// Print one setting, like transport.quic, or all of them.
+    Get(ConfigGet),
+    /// Change a setting. The value is read as toml, or else as a string.
+    Set(ConfigSet),
+    /// Go back to the default of an optional setting.
+    Unset(ConfigUnset),
+}
+#[derive(Clap)]
+pub struct ConfigGet {
+    key: Option<String>,
 }
-use semver::Version;
+#[derive(Clap)]
+pub struct ConfigSet {
+    key: String,
+    value: String,
+}
+#[derive(Clap)]
+pub struct ConfigUnset {
+    key: String,
+}
+use semver::{Version, VersionReq};
 #[derive(Debug)]
 struct PackageMetaData {
     name: String,
     version: Version,
+    /// Packaging revision of `version`, bumped for rebuilds of the same
+    /// upstream sources.
+    revision: u64,
     description: String,
 }
 #[derive(Debug)]
-struct ProjectConfig {
+pub struct ProjectConfig {
     flags: Vec<String>,
     archs: Vec<String>,
     enums: Vec<(String, Vec<String>)>,
@@ -60,16 +283,261 @@ pub struct RepoMetaData {
     name: String,
     key: String,
     address: String,
+    /// Keys of the addresses the repo had before rotate-key. They stay in
+    /// the node so the pointers published there keep being republished.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    moved_from: Vec<String>,
+    /// Address of the repo this one is a mirror of. Its signatures are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror_of: Option<String>,
+}
+fn write_maintainers(index_path: &std::path::Path, maintainers: &crate::index::Maintainers) {
+    let mut path = index_path.to_path_buf();
+    path.push("maintainers.toml");
+    std::fs::write(&path, toml::to_string(maintainers).unwrap()).unwrap();
+}
+
+fn read_repo_meta(path_to_repo: &std::path::Path) -> RepoMetaData {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("index");
+    path.push("meta.toml");
+    toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+}
+
+fn write_repo_meta(path_to_repo: &std::path::Path, repo_meta: &RepoMetaData) {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("index");
+    path.push("meta.toml");
+    std::fs::write(&path, toml::to_string(repo_meta).unwrap()).unwrap();
+}
+/// What the last push published, kept in published.toml in the repo root.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Published {
+    cid: String,
+    /// `fingerprint` of the index directory as it was pushed.
+    #[serde(default)]
+    fingerprint: String,
+    /// For a mirror, the upstream index it is a copy of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream_cid: Option<String>,
+    /// The moved.toml pointers rotate-key published at the old addresses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    moved: Vec<String>,
+}
+
+/// The repo's settings, kept in repo.toml in the repo root.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Port of the node's API.
+    port: u16,
+    swarm_port: u16,
+    /// zstd level the package variants are compressed with, 1 to 22.
+    #[serde(default = "default_compression_level")]
+    compression_level: u32,
+    /// How long a published IPNS record stays valid, like 24h.
+    #[serde(default = "default_publish_lifetime")]
+    publish_lifetime: String,
+    /// Default for push --valid-days.
+    valid_days: Option<u64>,
+    /// Key file of a maintainer to sign the index with instead of the repo's
+    /// key. Publishing it still takes the repo's key.
+    signing_key: Option<PathBuf>,
+    #[serde(default)]
+    transport: Transport,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transport {
+    /// Also listen for QUIC on the swarm port.
+    #[serde(default)]
+    quic: bool,
+    /// Addresses to announce to other peers instead of the ones the node
+    /// finds by itself, for nodes behind NAT.
+    #[serde(default)]
+    announce: Vec<String>,
+}
+
+fn default_compression_level() -> u32 {
+    3
+}
+
+fn default_publish_lifetime() -> String {
+    "24h".to_string()
+}
+
+/// What is wrong with `config`, if anything. The lifetime and addresses end
+/// up on an ipfs command line, so they are held to their own syntax.
+fn check_repo_config(config: &RepoConfig) -> Result<(), String> {
+    if !(1..=22).contains(&config.compression_level) {
+        return Err(format!(
+            "compression_level is {}, it must be from 1 to 22",
+            config.compression_level
+        ));
+    }
+    if !is_duration(&config.publish_lifetime) {
+        return Err(format!(
+            "publish_lifetime is {:?}, it must be a duration like 24h or 1h30m",
+            config.publish_lifetime
+        ));
+    }
+    for addr in config.transport.announce.iter() {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || "/.:-_".contains(c);
+        if !addr.starts_with('/') || !addr.chars().all(allowed) {
+            return Err(format!(
+                "{:?} in transport.announce is not a multiaddr like /ip4/1.2.3.4/tcp/4001",
+                addr
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `s` is a duration as ipfs takes them: numbers each followed by
+/// a unit from ns to h.
+fn is_duration(s: &str) -> bool {
+    let mut rest = s;
+    while !rest.is_empty() {
+        let number = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if number == 0 {
+            return false;
+        }
+        rest = &rest[number..];
+        match ["ns", "us", "ms", "s", "m", "h"]
+            .iter()
+            .find(|u| rest.starts_with(*u))
+        {
+            Some(unit) => rest = &rest[unit.len()..],
+            None => return false,
+        }
+    }
+    !s.is_empty()
+}
+
+/// The repo's settings. Repos created before repo.toml existed get their
+/// ports from the node's config and defaults for everything else.
+fn read_repo_config(path_to_repo: &std::path::Path) -> RepoConfig {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("repo.toml");
+    match std::fs::read_to_string(&path) {
+        Ok(s) => {
+            let config = toml::from_str(&s).unwrap_or_else(|e| panic!("repo.toml is invalid: {}", e));
+            check_repo_config(&config).unwrap_or_else(|e| panic!("repo.toml is invalid: {}", e));
+            config
+        }
+        Err(_) => {
+            // The swarm addresses look like "/ip4/0.0.0.0/tcp/16462".
+            let swarm = ipfs(path_to_repo, "config Addresses.Swarm");
+            let swarm_port = swarm
+                .split('"')
+                .filter_map(|a| a.split("/tcp/").nth(1))
+                .find_map(|p| p.parse().ok())
+                .expect("Could not read the swarm port from the ipfs config");
+            RepoConfig {
+                port: ipfs_api_port(path_to_repo),
+                swarm_port,
+                compression_level: default_compression_level(),
+                publish_lifetime: default_publish_lifetime(),
+                valid_days: None,
+                signing_key: None,
+                transport: Transport::default(),
+            }
+        }
+    }
+}
+
+fn write_repo_config(path_to_repo: &std::path::Path, config: &RepoConfig) {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("repo.toml");
+    std::fs::write(&path, toml::to_string(config).unwrap()).unwrap();
+}
+
+/// Write the ports and transport settings into the node's ipfs config.
+/// A running daemon only picks them up once restarted.
+fn apply_repo_config(path_to_repo: &std::path::Path, config: &RepoConfig) {
+    let mut swarm = vec![
+        format!("/ip4/0.0.0.0/tcp/{}", config.swarm_port),
+        format!("/ip6/::/tcp/{}", config.swarm_port),
+    ];
+    if config.transport.quic {
+        swarm.push(format!("/ip4/0.0.0.0/udp/{}/quic-v1", config.swarm_port));
+        swarm.push(format!("/ip6/::/udp/{}/quic-v1", config.swarm_port));
+    }
+    let json_list = |addrs: &[String]| {
+        let quoted: Vec<String> = addrs.iter().map(|a| format!("\"{}\"", a)).collect();
+        format!("'[{}]'", quoted.join(","))
+    };
+    ipfs(
+        path_to_repo,
+        &format!("config --json Addresses.Swarm {}", json_list(&swarm)),
+    );
+    ipfs(
+        path_to_repo,
+        &format!("config Addresses.API /ip4/127.0.0.1/tcp/{}", config.port),
+    );
+    ipfs(
+        path_to_repo,
+        &format!(
+            "config --json Addresses.Announce {}",
+            json_list(&config.transport.announce)
+        ),
+    );
+    ipfs(
+        path_to_repo,
+        &format!(
+            "config --json Swarm.Transports.Network.QUIC {}",
+            config.transport.quic
+        ),
+    );
+}
+
+fn read_published(path_to_repo: &std::path::Path) -> Option<Published> {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("published.toml");
+    std::fs::read_to_string(&path)
+        .ok()
+        .map(|s| toml::from_str(&s).expect("published.toml is corrupt"))
+}
+
+fn write_published(path_to_repo: &std::path::Path, published: &Published) {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("published.toml");
+    std::fs::write(&path, toml::to_string(published).unwrap()).unwrap();
+}
+
+/// The moved.toml pointers recorded so far, which every push carries over.
+fn published_moved(path_to_repo: &std::path::Path) -> Vec<String> {
+    read_published(path_to_repo).map(|p| p.moved).unwrap_or_default()
+}
+
+/// Record the moved.toml pointer `cid` so gc keeps it pinned.
+fn record_moved(path_to_repo: &std::path::Path, cid: &str) {
+    let mut published = read_published(path_to_repo).expect("The repo has not been pushed");
+    published.moved.push(cid.to_string());
+    write_published(path_to_repo, &published);
 }
 
 pub fn repository_cli(subcmd: Repository) {
     match subcmd {
         Repository::Add(a) => {
-            assert!(a.path_to_proj.is_dir());
             assert!(a.path_to_repo.is_dir());
+            let source = unpack_source(&a.path_to_repo, &a.path_to_proj, a.rev.as_deref());
+            let path_to_proj = match source.as_ref() {
+                Some(source) => source.proj_path.clone(),
+                None => a.path_to_proj.clone(),
+            };
+            assert!(path_to_proj.is_dir());
+            let problems = lint_project(&path_to_proj);
+            assert!(
+                problems.is_empty(),
+                "The project has problems:\n{}",
+                problems.join("\n")
+            );
             use std::fs::*;
             use toml::Value;
-            let mut proj_meta_path = a.path_to_proj.clone();
+            let mut proj_meta_path = path_to_proj.clone();
             proj_meta_path.push("meta.toml");
             let proj_meta = read_to_string(&proj_meta_path)
                 .unwrap()
@@ -79,77 +547,72 @@ pub fn repository_cli(subcmd: Repository) {
                 name: proj_meta["name"].as_str().unwrap().to_string(),
                 version: Version::parse(proj_meta["version"].as_str().unwrap())
                     .expect("Invalid version string"),
+                revision: a.revision.unwrap_or_else(|| {
+                    proj_meta
+                        .get("revision")
+                        .and_then(|r| r.as_integer())
+                        .unwrap_or(0) as u64
+                }),
                 description: proj_meta["description"]
                     .as_str()
                     .unwrap_or(proj_meta["name"].as_str().unwrap())
                     .to_string(),
             };
-            println!("{:?}", metadata);
 
-            let mut proj_conf_path = a.path_to_proj.clone();
+            let mut proj_conf_path = path_to_proj.clone();
             proj_conf_path.push("config.toml");
-            let proj_conf = read_to_string(&proj_conf_path)
-                .unwrap()
-                .parse::<Value>()
-                .unwrap();
-            assert!(
-                proj_conf.as_table().unwrap().contains_key("enums"),
-                "Found no enums = [] in config.toml"
-            );
-            assert!(
-                proj_conf.as_table().unwrap().contains_key("archs"),
-                "Found no archs = [] in config.toml"
-            );
-            assert!(
-                proj_conf.as_table().unwrap().contains_key("flags"),
-                "Found no flags = [] in config.toml"
-            );
-            let mut enums = Vec::new();
-            for e in proj_conf["enums"]
-                .as_array()
-                .expect("Found no enums = [] in config.toml")
-                .iter()
-                .map(|x| x.as_str().unwrap().to_string())
-            {
-                assert!(
-                    proj_conf.as_table().unwrap().contains_key(&e),
-                    format!("There was no enum name {} in config.toml", &e),
-                );
-                enums.push((
-                    e.clone(),
-                    proj_conf[&e]
-                        .as_array()
-                        .unwrap()
-                        .iter()
-                        .map(|x| x.as_str().unwrap().to_string())
-                        .collect(),
-                ));
-            }
+            let configdata = read_project_config(&proj_conf_path);
 
-            let configdata = ProjectConfig {
-                flags: proj_conf["flags"]
-                    .as_array()
-                    .unwrap()
+            let mut index_path = a.path_to_repo.clone();
+            index_path.push("index");
+            let subpackages = proj_meta
+                .get("subpackage")
+                .and_then(|s| s.as_array())
+                .cloned()
+                .unwrap_or_default();
+            // Check every package is new before writing any of them.
+            let mut names = vec![metadata.name.clone()];
+            names.extend(
+                subpackages
                     .iter()
-                    .map(|x| x.as_str().unwrap().to_string())
-                    .collect(),
-                archs: proj_conf["archs"]
-                    .as_array()
-                    .expect("Found no archs = [] in config.toml")
-                    .iter()
-                    .map(|x| x.as_str().unwrap().to_string())
-                    .collect(),
-                enums: enums,
-            };
-            println!("{:?}", configdata);
-
-            let mut dest_path = a.path_to_repo.clone();
-            dest_path.push("index");
-            dest_path.push("pkgs");
-            dest_path.push(&metadata.name);
-            dest_path.push(&format!("{}", metadata.version.major));
-            dest_path.push(&format!("{}", metadata.version));
-            println!("{:?}", dest_path);
+                    .map(|s| s["name"].as_str().unwrap().to_string()),
+            );
+            // A subpackage may only take over a name it was published under
+            // before, so it cannot shadow another project's package.
+            for name in names.iter().skip(1) {
+                if let Some((version, revision)) = crate::index::revisions_of(&index_path, name).pop() {
+                    let mut path = index_path.clone();
+                    path.push("pkgs");
+                    path.push(name);
+                    path.push(format!("{}", version.major));
+                    path.push(crate::index::version_dir(&version, revision));
+                    path.push("meta.toml");
+                    let meta = read_to_string(&path).unwrap().parse::<Value>().unwrap();
+                    assert!(
+                        meta.get("split_from").and_then(|s| s.as_str()) == Some(metadata.name.as_str()),
+                        "{} is already a package in the repo that is not split from {}. Give the subpackage another name.",
+                        name,
+                        metadata.name
+                    );
+                }
+            }
+            let claims: Vec<(PathBuf, Option<PathBuf>)> = names
+                .iter()
+                .map(|name| {
+                    claim_version_dir(
+                        &index_path,
+                        name,
+                        &metadata.version,
+                        metadata.revision,
+                        a.force,
+                    )
+                })
+                .collect();
+            // Everything is built next to the index and only moved in once
+            // it all is, so a failed build leaves the index as it was.
+            let staging = Staging::new(&a.path_to_repo);
+            let dest_paths: Vec<PathBuf> = names.iter().map(|name| staging.path.join(name)).collect();
+            let dest_path = dest_paths[0].clone();
 
             create_dir_all(&dest_path);
             let mut dest_meta_path = dest_path.clone();
@@ -160,71 +623,36 @@ pub fn repository_cli(subcmd: Repository) {
             dest_index_path.push("index.toml");
 
             copy(&proj_meta_path, &dest_meta_path).unwrap();
-            copy(&proj_conf_path, &dest_conf_path).unwrap();
-
-            let build_ops: Vec<Vec<(String, String)>> = {
-                //Will one day get extracted
-                let mut options: Vec<(String, Vec<String>)> = Vec::new();
-                for flag in configdata.flags.iter() {
-                    options.push((flag.to_string(), vec!["".to_owned(), "1".to_owned()]));
+            if source.is_some() || a.revision.is_some() {
+                let mut meta = proj_meta.clone();
+                let table = meta.as_table_mut().unwrap();
+                if let Some(source) = source.as_ref() {
+                    // Record where the package was built from, so it can be
+                    // traced back to the exact upstream sources.
+                    table.insert("source".to_string(), Value::Table(source.table.clone()));
                 }
-
-                if configdata.archs.len() > 0 {
-                    options.push(("archs".to_owned(), configdata.archs.clone()));
-                }
-                options.extend_from_slice(&configdata.enums);
-                let mut option_counts = Vec::new();
-                for x in options.iter() {
-                    option_counts.push(x.1.len());
-                }
-
-                for o in options.iter() {
-                    assert!(
-                        o.1.len() > 0,
-                        format!("Enum {} must have atleast one possible value.", &o.0)
+                if metadata.revision > 0 {
+                    table.insert(
+                        "revision".to_string(),
+                        Value::Integer(metadata.revision as i64),
                     );
+                } else {
+                    table.remove("revision");
                 }
-                let mut current_option = vec![0; options.len()];
+                write(&dest_meta_path, toml::to_string(&meta).unwrap()).unwrap();
+            }
+            copy(&proj_conf_path, &dest_conf_path).unwrap();
 
-                let mut all_options = Vec::new();
-                all_options.push(current_option.clone());
-                let mut digit = 0;
-                loop {
-                    if digit >= current_option.len() {
-                        break;
-                    }
-                    current_option[digit] += 1;
-                    if current_option[digit] >= option_counts[digit] {
-                        current_option[digit] = 0;
-                        digit += 1;
-                    } else {
-                        digit = 0;
-                        all_options.push(current_option.clone());
-                    }
-                }
-                all_options
-                    .iter()
-                    .map(|ao| {
-                        ao.iter()
-                            .enumerate()
-                            .map(|(i, v)| (options[i].0.clone(), options[i].1[*v].clone()))
-                            .collect()
-                    })
-                    .collect()
-            };
+            let build_ops = build_options(&configdata);
+            let repo_config = read_repo_config(&a.path_to_repo);
 
             //create build file
-            let mut proj_build_file_path = a.path_to_proj.clone();
+            let mut proj_build_file_path = path_to_proj.clone();
             proj_build_file_path.push("sps_build.sh");
             let build_file_string = read_to_string(&proj_build_file_path)
                 .expect("Missing sps_build.sh file in project.");
 
-            let copy_options = {
-                let mut copy_options = fs_extra::dir::CopyOptions::new();
-                copy_options.copy_inside = true; // Equivilant to cp -r
-                copy_options.overwrite = true;
-                copy_options
-            };
+            let files = project_files(&path_to_proj, &proj_meta);
 
             let open_options = {
                 let mut open_options = OpenOptions::new();
@@ -243,23 +671,19 @@ pub fn repository_cli(subcmd: Repository) {
             let mut index_file = BufWriter::new(index_file);
 
             for (index, b) in build_ops.iter().enumerate() {
-                println!("{:?}", b);
 
                 let mut out_path = dest_path.clone();
                 out_path.push(format!("{}", index));
                 create_dir_all(&out_path).unwrap();
-                fs_extra::dir::copy(&a.path_to_proj, &out_path, &copy_options).unwrap();
-
-                //Remove dups
-                {
-                    out_path.push("meta.toml");
-                    remove_file(&out_path);
-                    out_path.pop();
-                }
-                {
-                    out_path.push("config.toml");
-                    remove_file(&out_path);
-                    out_path.pop();
+                copy_project(&path_to_proj, &out_path, &files);
+                for hook in HOOKS.iter() {
+                    let mut from = path_to_proj.clone();
+                    from.push(hook);
+                    if from.is_file() {
+                        let mut to = out_path.clone();
+                        to.push(hook);
+                        copy(&from, &to).unwrap();
+                    }
                 }
 
                 //write build file
@@ -277,117 +701,126 @@ pub fn repository_cli(subcmd: Repository) {
                         f.write(format!("SPS_CONFIG_{}={}\n", key, val).as_bytes())
                             .unwrap();
                     }
+                    for name in names.iter().skip(1) {
+                        f.write_all(
+                            format!(
+                                "SPS_SUBPACKAGE_DIR_{}=\"$SPS_SPLIT_DIR/{}\"\n",
+                                subpackage_var(name),
+                                name
+                            )
+                            .as_bytes(),
+                        )
+                        .unwrap();
+                    }
                     f.write("\n".as_bytes()).unwrap();
                     f.write(build_file_string.as_bytes()).unwrap();
                     f.flush();
                     out_path.pop();
                 }
-                tar_and_zstd_dir(&out_path);
+                tar_and_zstd_dir(&out_path, repo_config.compression_level);
                 remove_dir_all(&out_path).unwrap();
                 out_path.pop();
                 out_path.push(format!("{}.tar.zst", index));
+                println!(
+                    "Variant {} is {} files, {} packed.",
+                    index,
+                    files.len(),
+                    human_size(std::fs::metadata(&out_path).unwrap().len())
+                );
 
                 let hash = ipfs_add_and_rm(&a.path_to_repo, &out_path);
                 index_file
                     .write(format!("{} = \"{}\"\n", index, &hash).as_bytes())
                     .unwrap();
             }
-        }
-        Repository::Daemon(d) => {
-            let exit_status = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(format!(
-                    "IPFS_PATH={}/ipfs ipfs daemon",
-                    d.path_to_repo.to_str().unwrap()
-                ))
-                .spawn()
-                .expect("failed to execute process")
-                .wait()
-                .unwrap();
-            assert!(exit_status.success());
-        }
-        Repository::Push(p) => {
-            let mut repo_index_path = p.path_to_repo.clone();
-            repo_index_path.push("index");
-
-            let meta_data = {
-                use std::fs::*;
-
-                repo_index_path.push("meta.toml");
-                let repo_meta : RepoMetaData =
-           toml::from_str(&read_to_string(&repo_index_path).unwrap()).unwrap();
-
-                repo_index_path.pop();
-                repo_meta
-            };
-            //tar_and_zstd_dir(&repo_index_path);
-            //repo_index_path.pop();
-            //repo_index_path.push(format!("{}.tar.zst", "index"));
+            index_file.flush().unwrap();
 
-            let hash = ipfs_add_recursive(&p.path_to_repo, &repo_index_path);
-            //std::fs::remove_file(&repo_index_path);
-            
-            println!("Publishing to ipfs...");
-            let pub_hash = ipfs_name_publish(&p.path_to_repo, &meta_data.key, &hash);
+            // Subpackages share the variants of the package they are split
+            // from. Clients tell their files apart after building.
+            for (sub, sub_path) in subpackages.iter().zip(dest_paths.iter().skip(1)) {
+                create_dir_all(sub_path).unwrap();
+                let mut meta = sub.clone();
+                let table = meta.as_table_mut().unwrap();
+                table.insert(
+                    "version".to_string(),
+                    Value::String(metadata.version.to_string()),
+                );
+                if metadata.revision > 0 {
+                    table.insert(
+                        "revision".to_string(),
+                        Value::Integer(metadata.revision as i64),
+                    );
+                }
+                table
+                    .entry("description".to_string())
+                    .or_insert_with(|| Value::String(metadata.description.clone()));
+                table.insert(
+                    "split_from".to_string(),
+                    Value::String(metadata.name.clone()),
+                );
+                if let Some(source) = source.as_ref() {
+                    table.insert("source".to_string(), Value::Table(source.table.clone()));
+                }
+                let mut path = sub_path.clone();
+                path.push("meta.toml");
+                write(&path, toml::to_string(&meta).unwrap()).unwrap();
+                path.pop();
+                path.push("config.toml");
+                copy(&proj_conf_path, &path).unwrap();
+                path.pop();
+                path.push("index");
+                copy(&index_path, &path).unwrap();
+                println!("Split {} off {}.", sub["name"].as_str().unwrap(), metadata.name);
+            }
+            for (staged, (dest, replaced)) in dest_paths.iter().zip(claims.iter()) {
+                if let Some(replaced) = replaced {
+                    remove_dir_all(replaced).unwrap();
+                }
+                create_dir_all(dest.parent().unwrap()).unwrap();
+                rename(staged, dest).unwrap();
+            }
 
-            println!(
-                "here's the published hash, {} . Here's the reference hash, {} .",
-                &pub_hash, &meta_data.address
-            );
+            let mut index_path = a.path_to_repo.clone();
+            index_path.push("index");
+            for (name, meta) in names.iter().zip(std::iter::once(&proj_meta).chain(subpackages.iter())) {
+                for provided in read_provides(meta) {
+                    add_provider(&index_path, &provided, name);
+                    println!("{} provides {}.", name, provided);
+                }
+            }
         }
-        Repository::New(n) => {
-            if n.path_to_repo.exists() {
-                assert!(false, "Big bad. It exists");
+        Repository::Daemon(d) => match d.action {
+            None => {
+                let exit_status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(format!(
+                        "IPFS_PATH={}/ipfs ipfs daemon",
+                        d.path_to_repo.to_str().unwrap()
+                    ))
+                    .spawn()
+                    .expect("failed to execute process")
+                    .wait()
+                    .unwrap();
+                assert!(exit_status.success());
             }
-            use std::fs::*;
-            use uuid::Uuid;
-            let mut path = n.path_to_repo.clone();
-            path.push("index");
-            create_dir_all(&path).unwrap();
-
-            let exit_status = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(format!(
-"export IPFS_PATH={}/ipfs && ipfs init && 
-ipfs config --json Addresses '{{\"Swarm\":[\"/ip4/0.0.0.0/tcp/{}\",\"/ip6/::/tcp/{}\"],\"API\":\"/ip4/127.0.0.1/tcp/{}\"}}'",
-     n.path_to_repo.to_str().unwrap(), n.swarm_port, n.swarm_port,
-            n.port))
-        .spawn()
-        .expect("failed to execute process")
-        .wait().unwrap();
-            assert!(exit_status.success());
-
-            path.push("meta.toml");
-
-            let name = path
-                .parent()
-                .unwrap()
-                .parent()
-                .unwrap()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap();
-
-            let key = format!(
-                "{}-{}",
-                name,
-                Uuid::new_v4()
-                    .to_simple()
-                    .encode_lower(&mut Uuid::encode_buffer())
-            );
-            let address = ipfs_key_gen(&n.path_to_repo, &key);
-            write(
-                &path,
-                &format!(
-                    "name = \"{}\"
-key = \"{}\"
-address = \"{}\"
-",
-                    name, &key, &address
+            Some(DaemonAction::Start) => start_daemon(&d.path_to_repo),
+            Some(DaemonAction::Stop) => stop_daemon(&d.path_to_repo),
+            Some(DaemonAction::Status) => match daemon_pid(&d.path_to_repo) {
+                Some(pid) if daemon_reachable(&d.path_to_repo) => println!(
+                    "The daemon is running with pid {} on port {}.",
+                    pid,
+                    ipfs_api_port(&d.path_to_repo)
                 ),
-            );
-        }
+                Some(pid) => println!("The daemon is starting with pid {}.", pid),
+                None if daemon_reachable(&d.path_to_repo) => println!(
+                    "A daemon not started by sps is running on port {}.",
+                    ipfs_api_port(&d.path_to_repo)
+                ),
+                None => println!("The daemon is not running."),
+            },
+        },
+        Repository::Push(p) => push(&p.path_to_repo, p.valid_days),
+        Repository::New(n) => new_repo(&n.path_to_repo, n.port, n.swarm_port),
         Repository::Delete(d) => {
             if !d.path_to_repo.exists() {
                 assert!(false, "Big bad. It does not exists");
@@ -397,30 +830,1975 @@ address = \"{}\"
             path.push("index");
             path.push("meta.toml");
 
-            let repo = read_to_string(&path).unwrap();
-            use toml::Value;
-
-            let repo = repo.parse::<Value>().unwrap(); // Return type Value::Table
-            let key = repo["key"].as_str().unwrap();
+            let repo_meta: RepoMetaData = toml::from_str(&read_to_string(&path).unwrap()).unwrap();
             path.pop();
             path.pop();
-            ipfs_key_rm(&d.path_to_repo, key);
+
+            if let Some(key_path) = d.export_key.as_ref() {
+                assert!(!key_path.exists(), "{:?} already exists", key_path);
+            }
+            if !d.force {
+                println!(
+                    "This deletes {:?} and the key of {}. Unless the key is exported, nothing can ever be published to that address again.",
+                    path, repo_meta.address
+                );
+                println!("Type the name of the repo, {}, to go ahead:", repo_meta.name);
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer).unwrap();
+                if answer.trim() != repo_meta.name {
+                    println!("Not deleting anything.");
+                    return;
+                }
+            }
+            // The key commands need the repo lock the daemon holds.
+            if daemon_pid(&d.path_to_repo).is_some() {
+                stop_daemon(&d.path_to_repo);
+            }
+            if let Some(key_path) = d.export_key.as_ref() {
+                ipfs(
+                    &d.path_to_repo,
+                    &format!(
+                        "key export -o {} {}",
+                        key_path.to_str().unwrap(),
+                        repo_meta.key
+                    ),
+                );
+                println!("Exported the key of {} to {:?}.", repo_meta.address, key_path);
+            }
+            ipfs_key_rm(&d.path_to_repo, &repo_meta.key);
+            for key in repo_meta.moved_from.iter() {
+                ipfs_key_rm(&d.path_to_repo, key);
+            }
             remove_dir_all(&path).unwrap();
         }
-        _ => (),
-    }
-}
+        Repository::Maintainer(m) => {
+            let mut index_path = m.path_to_repo.clone();
+            index_path.push("index");
+            let mut maintainers = crate::index::read_maintainers(&index_path);
+            match m.action {
+                MaintainerAction::List => {
+                    println!("repo key {}", read_repo_meta(&m.path_to_repo).address);
+                    for (name, key) in maintainers.maintainers.iter() {
+                        println!("{} {}", name, key);
+                    }
+                    return;
+                }
+                MaintainerAction::Add(a) => {
+                    assert!(
+                        !maintainers.maintainers.contains_key(&a.name),
+                        "{} is already a maintainer",
+                        a.name
+                    );
+                    maintainers.maintainers.insert(a.name, a.key);
+                }
+                MaintainerAction::Revoke(r) => {
+                    assert!(
+                        maintainers.maintainers.remove(&r.name).is_some(),
+                        "{} is not a maintainer",
+                        r.name
+                    );
+                }
+                MaintainerAction::NewKey(n) => {
+                    assert!(!n.key_file.exists(), "{:?} already exists", n.key_file);
+                    let name = format!(
+                        "sps-signing-{}",
+                        n.key_file.file_stem().unwrap().to_str().unwrap()
+                    );
+                    let key = ipfs(&m.path_to_repo, &format!("key gen {}", name));
+                    ipfs(
+                        &m.path_to_repo,
+                        &format!("key export -o {} {}", n.key_file.to_str().unwrap(), name),
+                    );
+                    println!("Created the signing key {}.", key);
+                    return;
+                }
+            }
+            maintainers.serial += 1;
+            write_maintainers(&index_path, &maintainers);
+            println!("Push the repository for the change to reach clients. The push signs the list with the repo's key.");
+        }
+        Repository::RotateKey(r) => {
+            let mut repo_meta = read_repo_meta(&r.path_to_repo);
+            let old_key = repo_meta.key.clone();
+            let old_address = repo_meta.address.clone();
+            if !daemon_reachable(&r.path_to_repo) {
+                start_daemon(&r.path_to_repo);
+            }
 
-fn tar_and_zstd_dir(dir_path: &std::path::Path) {
+            use uuid::Uuid;
+            let key = format!(
+                "{}-{}",
+                repo_meta.name,
+                Uuid::new_v4()
+                    .to_simple()
+                    .encode_lower(&mut Uuid::encode_buffer())
+            );
+            repo_meta.address = ipfs_key_gen(&r.path_to_repo, &key);
+            repo_meta.key = key;
+            repo_meta.moved_from.push(old_key.clone());
+            write_repo_meta(&r.path_to_repo, &repo_meta);
+            // Publish at the new address before pointing the old one there.
+            push(&r.path_to_repo, None);
+
+            let mut moved_path = r.path_to_repo.clone();
+            moved_path.push("moved");
+            std::fs::create_dir_all(&moved_path).unwrap();
+            let mut moved_toml_path = moved_path.clone();
+            moved_toml_path.push("moved.toml");
+            std::fs::write(
+                &moved_toml_path,
+                toml::to_string(&crate::index::Moved {
+                    from: old_address.clone(),
+                    to: repo_meta.address.clone(),
+                })
+                .unwrap(),
+            )
+            .unwrap();
+            sign(&r.path_to_repo, &moved_path, "moved.toml", "signature.toml", &old_key);
+            let cid = ipfs(
+                &r.path_to_repo,
+                &format!("add --cid-version 1 -rQ {}", moved_path.to_str().unwrap()),
+            );
+            std::fs::remove_dir_all(&moved_path).unwrap();
+            record_moved(&r.path_to_repo, &cid);
+            ipfs_name_publish(
+                &r.path_to_repo,
+                &old_key,
+                &cid,
+                &read_repo_config(&r.path_to_repo).publish_lifetime,
+            );
+            println!(
+                "Moved from {} to {}. Clients follow on their next update.",
+                old_address, repo_meta.address
+            );
+        }
+        Repository::Mirror(m) => {
+            if !m.path_to_repo.exists() {
+                new_repo(&m.path_to_repo, m.port, m.swarm_port);
+            }
+            let minutes = match m.every {
+                Some(minutes) => minutes,
+                None => return mirror(&m.path_to_repo, &m.upstream),
+            };
+            loop {
+                // A failed sync, say because upstream could not be reached,
+                // is tried again next time rather than ending the loop.
+                if std::panic::catch_unwind(|| mirror(&m.path_to_repo, &m.upstream)).is_err() {
+                    eprintln!("Syncing the mirror failed, trying again in {} minutes.", minutes);
+                }
+                std::thread::sleep(std::time::Duration::from_secs(minutes * 60));
+            }
+        }
+        Repository::InitProject(i) => {
+            use std::fs::*;
+            let name = match i.name.clone() {
+                Some(name) => name,
+                None => i
+                    .path_to_proj
+                    .canonicalize()
+                    .unwrap_or_else(|_| i.path_to_proj.clone())
+                    .file_name()
+                    .expect("Pass --name, the directory has no name to use")
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            };
+            if let Some(problem) = check_package_name(&name) {
+                panic!("{}", problem);
+            }
+            Version::parse(&i.version)
+                .unwrap_or_else(|e| panic!("{} is not a valid version: {}", i.version, e));
+            for file in ["meta.toml", "config.toml", "sps_build.sh"].iter() {
+                let mut path = i.path_to_proj.clone();
+                path.push(file);
+                assert!(!path.exists(), "{:?} already exists", path);
+            }
+            create_dir_all(&i.path_to_proj).unwrap();
+
+            let mut path = i.path_to_proj.clone();
+            path.push("meta.toml");
+            write(
+                &path,
+                format!(
+                    "name = \"{}\"
+version = \"{}\"
+description = \"{}\"
+
+# Installed files that are configuration, in .gitignore syntax. Edits to
+# them are kept on upgrade, with the new version put next to them.
+# config_files = [\"etc/{}.conf\"]
+
+# Packages this one needs, by name and version requirement, like
+# libfoo = \"^1.2\".
+[dependencies]
+
+# Parts split off into packages of their own. Installed files matching
+# files, in .gitignore syntax, go to the subpackage, as do the ones
+# sps_build.sh puts in $SPS_SUBPACKAGE_DIR_<NAME>.
+# [[subpackage]]
+# name = \"{}-doc\"
+# description = \"Documentation for {}\"
+# files = [\"usr/share/doc/\"]
+",
+                    name, i.version, name, name, name, name
+                ),
+            )
+            .unwrap();
+            path.pop();
+            path.push("config.toml");
+            write(
+                &path,
+                "# Every combination of the options below is built as a variant of its own.
+# Each flag is built both set to 1 and left empty, as SPS_CONFIG_<flag>.
+flags = []
+# Architectures to build for, as SPS_CONFIG_archs. Clients install the
+# variant for their own.
+archs = []
+# Names of enums. Each one lists its values in a key of the same name, like
+# enums = [\"ssl\"] and ssl = [\"openssl\", \"libressl\"].
+enums = []
+",
+            )
+            .unwrap();
+            path.pop();
+            path.push("sps_build.sh");
+            write(
+                &path,
+                format!(
+                    "# Build the package and install it into $SPS_DEST_DIR, laid out the way
+# it should end up under the root, like $SPS_DEST_DIR/usr/bin/{}.
+# The SPS_CONFIG_ variables set above say which variant is being built.
+set -e
+mkdir -p $SPS_DEST_DIR/usr/bin
+",
+                    name
+                ),
+            )
+            .unwrap();
+            println!("Created {} {} in {:?}.", name, i.version, i.path_to_proj);
+        }
+        Repository::LintProject(l) => {
+            let problems = lint_project(&l.path_to_proj);
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            let mut conf_path = l.path_to_proj.clone();
+            conf_path.push("config.toml");
+            println!(
+                "The project is fine, it builds {} variants.",
+                build_options(&read_project_config(&conf_path)).len()
+            );
+        }
+        Repository::ImportKey(i) => {
+            let mut meta_path = i.path_to_repo.clone();
+            meta_path.push("index");
+            meta_path.push("meta.toml");
+            let mut repo_meta: RepoMetaData =
+                toml::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+
+            let key = i.key_file.file_stem().unwrap().to_str().unwrap().to_string();
+            assert!(
+                !ipfs(&i.path_to_repo, "key list").lines().any(|k| k == key),
+                "The repo already has a key named {}. Rename {:?} to import it.",
+                key,
+                i.key_file
+            );
+            // The old key is only worth keeping once something was published
+            // at its address.
+            let published = read_published(&i.path_to_repo).is_some();
+            assert!(
+                !published || (i.force && i.export_old_key.is_some()),
+                "This replaces the key of {}, and nothing can be published there again without it. Pass --force and --export-old-key <file> to save it first.",
+                repo_meta.address
+            );
+            if let Some(old_key_path) = i.export_old_key.as_ref() {
+                assert!(!old_key_path.exists(), "{:?} already exists", old_key_path);
+            }
+
+            if !daemon_reachable(&i.path_to_repo) {
+                start_daemon(&i.path_to_repo);
+            }
+            let address = ipfs(
+                &i.path_to_repo,
+                &format!("key import {} {}", key, i.key_file.to_str().unwrap()),
+            );
+            // Clients refuse an index older than the one they have seen, so
+            // carry on from the generation that was last published. Looked up
+            // while the daemon can still reach the network.
+            meta_path.pop();
+            meta_path.push("manifest.toml");
+            match try_ipfs(&i.path_to_repo, &format!("name resolve {}", address))
+                .and_then(|cid| try_ipfs(&i.path_to_repo, &format!("cat {}/manifest.toml", cid)))
+            {
+                Some(manifest) => {
+                    std::fs::write(&meta_path, manifest).unwrap();
+                    println!("Picked up the manifest last published there.");
+                }
+                None => println!(
+                    "Could not fetch what was last published there. Clients will refuse the index until it is pushed as many times as before."
+                ),
+            }
+            if daemon_pid(&i.path_to_repo).is_some() {
+                stop_daemon(&i.path_to_repo);
+            }
+
+            if let Some(old_key_path) = i.export_old_key.as_ref() {
+                ipfs(
+                    &i.path_to_repo,
+                    &format!(
+                        "key export -o {} {}",
+                        old_key_path.to_str().unwrap(),
+                        repo_meta.key
+                    ),
+                );
+                println!("Exported the key of {} to {:?}.", repo_meta.address, old_key_path);
+            }
+            ipfs_key_rm(&i.path_to_repo, &repo_meta.key);
+            repo_meta.key = key;
+            repo_meta.address = address;
+            write_repo_meta(&i.path_to_repo, &repo_meta);
+            println!("The repo now publishes to {}.", repo_meta.address);
+        }
+        Repository::Yank(y) => {
+            let path = published_version_path(&y.path_to_repo, &y.name, &y.version);
+            mark_version(&path, "yanked", y.reason, y.undo);
+            println!("Push the repository for the change to reach clients.");
+        }
+        Repository::Deprecate(d) => {
+            let path = published_version_path(&d.path_to_repo, &d.name, &d.version);
+            mark_version(&path, "deprecated", d.reason, d.undo);
+            println!("Push the repository for the change to reach clients.");
+        }
+
+        Repository::RemovePackage(r) => {
+            use std::fs::*;
+            let mut path = r.path_to_repo.clone();
+            path.push("index");
+            path.push("pkgs");
+            path.push(&r.name);
+            let mut depth = 0;
+            if let Some(version) = r.version {
+                match version.parse::<u64>() {
+                    Ok(major) => {
+                        path.push(format!("{}", major));
+                        depth = 1;
+                    }
+                    Err(_) => {
+                        path = published_version_path(&r.path_to_repo, &r.name, &version);
+                        depth = 2;
+                    }
+                }
+            }
+            assert!(path.is_dir(), "{:?} is not in this repository", path);
+            remove_dir_all(&path).unwrap();
+            // Don't leave behind empty major and package directories.
+            for _ in 0..depth {
+                path.pop();
+                if read_dir(&path).unwrap().next().is_some() {
+                    break;
+                }
+                remove_dir(&path).unwrap();
+            }
+            let mut index_path = r.path_to_repo.clone();
+            index_path.push("index");
+            remove_provider(&index_path, &r.name);
+            println!("Push the repository for the change to reach clients, then run gc to unpin the artifacts.");
+        }
+        Repository::Gc(g) => {
+            let mut index_path = g.path_to_repo.clone();
+            index_path.push("index");
+            let published = read_published(&g.path_to_repo);
+            let mut published_path = g.path_to_repo.clone();
+            published_path.push("gc.tmp");
+            if published_path.exists() {
+                std::fs::remove_dir_all(&published_path).unwrap();
+            }
+            if let Some(published) = published.as_ref() {
+                ipfs(
+                    &g.path_to_repo,
+                    &format!(
+                        "get -o {} /ipfs/{}",
+                        published_path.to_str().unwrap(),
+                        published.cid
+                    ),
+                );
+            }
+            let keep = gc_keep(&index_path, published.as_ref(), &published_path);
+            if published_path.exists() {
+                std::fs::remove_dir_all(&published_path).unwrap();
+            }
+            let mut unpinned = 0;
+            for cid in ipfs_pin_ls(&g.path_to_repo) {
+                if keep.contains(&cid) {
+                    continue;
+                }
+                println!("unpin {}", cid);
+                if !g.dry_run {
+                    ipfs_pin_rm(&g.path_to_repo, &cid);
+                }
+                unpinned += 1;
+            }
+            if !g.dry_run && unpinned > 0 {
+                ipfs_repo_gc(&g.path_to_repo);
+            }
+        }
+
+        Repository::List(l) => {
+            let mut index_path = l.path_to_repo.clone();
+            index_path.push("index");
+            for (name, versions) in crate::index::package_versions(&index_path) {
+                let providers = crate::index::read_providers(&index_path, &name).providers;
+                if providers.is_empty() {
+                    println!("{}", name);
+                } else {
+                    println!("{}, provided by {}", name, providers.join(", "));
+                }
+                for (version, revision) in versions {
+                    let c =
+                        crate::index::read_candidate("", &index_path, &name, &version, revision);
+                    let mut variants_path = c.path.clone();
+                    variants_path.push("index");
+                    let variants = std::fs::read_to_string(&variants_path)
+                        .unwrap_or_default()
+                        .parse::<toml::Value>()
+                        .unwrap();
+                    let variants = variants.as_table().unwrap();
+                    let mut notes = String::new();
+                    if c.yanked.is_some() {
+                        notes.push_str(" (yanked)");
+                    }
+                    if c.deprecated.is_some() {
+                        notes.push_str(" (deprecated)");
+                    }
+                    println!(
+                        "    {}, {} variants{}",
+                        crate::index::display_version(&version, revision),
+                        variants.len(),
+                        notes
+                    );
+                    for (variant, cid) in variants.iter() {
+                        println!("        {} {}", variant, cid.as_str().unwrap());
+                    }
+                }
+            }
+        }
+        Repository::Status(st) => {
+            let mut repo_meta_path = st.path_to_repo.clone();
+            repo_meta_path.push("index");
+            repo_meta_path.push("meta.toml");
+            let repo_meta: RepoMetaData =
+                toml::from_str(&std::fs::read_to_string(&repo_meta_path).unwrap()).unwrap();
+            println!("name:      {}", repo_meta.name);
+            println!("key:       {}", repo_meta.key);
+            println!("address:   {}", repo_meta.address);
+
+            repo_meta_path.pop();
+            match read_published(&st.path_to_repo) {
+                Some(published) => {
+                    println!("published: {}", published.cid);
+                    if published.fingerprint == fingerprint(&repo_meta_path) {
+                        println!("The index is unchanged since it was pushed.");
+                    } else {
+                        println!("The index has changed since it was pushed.");
+                    }
+                }
+                None => println!("The index has never been pushed."),
+            }
+            if daemon_reachable(&st.path_to_repo) {
+                println!("The daemon is running on port {}.", ipfs_api_port(&st.path_to_repo));
+            } else {
+                println!("The daemon is not running.");
+            }
+        }
+        Repository::Config(c) => {
+            let config = read_repo_config(&c.path_to_repo);
+            let mut value = toml::Value::try_from(&config).unwrap();
+            let (key, new_value) = match c.action {
+                ConfigAction::Get(g) => {
+                    match g.key {
+                        Some(key) => {
+                            let mut v = &value;
+                            for part in key.split('.') {
+                                v = v
+                                    .get(part)
+                                    .unwrap_or_else(|| panic!("{} is not set", key));
+                            }
+                            match v {
+                                toml::Value::String(s) => println!("{}", s),
+                                toml::Value::Table(t) => print!("{}", toml::to_string(t).unwrap()),
+                                v => println!("{}", v),
+                            }
+                        }
+                        None => print!("{}", toml::to_string(&value).unwrap()),
+                    }
+                    return;
+                }
+                ConfigAction::Set(s) => {
+                    let new_value = format!("value = {}", s.value)
+                        .parse::<toml::Value>()
+                        .map(|t| t["value"].clone())
+                        .unwrap_or(toml::Value::String(s.value));
+                    (s.key, Some(new_value))
+                }
+                ConfigAction::Unset(u) => (u.key, None),
+            };
+
+            let mut parts: Vec<&str> = key.split('.').collect();
+            let last = parts.pop().unwrap();
+            let mut table = value.as_table_mut().unwrap();
+            for part in parts {
+                table = table
+                    .get_mut(part)
+                    .and_then(|t| t.as_table_mut())
+                    .unwrap_or_else(|| panic!("{} is not a setting", key));
+            }
+            match new_value {
+                Some(v) => {
+                    table.insert(last.to_string(), v);
+                }
+                None => {
+                    table.remove(last);
+                }
+            }
+            let config: RepoConfig = value
+                .try_into()
+                .unwrap_or_else(|e| panic!("Cannot set {}: {}", key, e));
+            check_repo_config(&config).unwrap_or_else(|e| panic!("Cannot set {}: {}", key, e));
+            apply_repo_config(&c.path_to_repo, &config);
+            write_repo_config(&c.path_to_repo, &config);
+            if daemon_pid(&c.path_to_repo).is_some() {
+                println!("Restart the daemon for the change to take effect.");
+            }
+        }
+    }
+}
+
+/// Create a repo with its own ipfs node and publishing key.
+fn new_repo(path_to_repo: &std::path::Path, port: u16, swarm_port: u16) {
+    if path_to_repo.exists() {
+        panic!("{:?} already exists", path_to_repo);
+    }
+    use std::fs::*;
+    use uuid::Uuid;
+    let mut path = path_to_repo.to_path_buf();
+    path.push("index");
+    create_dir_all(&path).unwrap();
+
+    let exit_status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs init",
+            path_to_repo.to_str().unwrap()
+        ))
+        .spawn()
+        .expect("failed to execute process")
+        .wait()
+        .unwrap();
+    assert!(exit_status.success());
+    let config = RepoConfig {
+        port,
+        swarm_port,
+        compression_level: default_compression_level(),
+        publish_lifetime: default_publish_lifetime(),
+        valid_days: None,
+        signing_key: None,
+        transport: Transport::default(),
+    };
+    apply_repo_config(path_to_repo, &config);
+    write_repo_config(path_to_repo, &config);
+
+    path.push("meta.toml");
+
+    let name = path
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap();
+
+    let key = format!(
+        "{}-{}",
+        name,
+        Uuid::new_v4()
+            .to_simple()
+            .encode_lower(&mut Uuid::encode_buffer())
+    );
+    let address = ipfs_key_gen(path_to_repo, &key);
+    write(
+        &path,
+        &format!(
+            "name = \"{}\"
+key = \"{}\"
+address = \"{}\"
+",
+            name, &key, &address
+        ),
+    )
+    .unwrap();
+}
+
+/// Bring the mirror at `path_to_repo` up to date with what `upstream`
+/// publishes. The upstream index is kept as it is, manifest and signature
+/// included, apart from meta.toml which names the mirror.
+fn mirror(path_to_repo: &std::path::Path, upstream: &str) {
+    use std::fs::*;
+    let mut index_path = path_to_repo.to_path_buf();
+    index_path.push("index");
+    let mut old_path = path_to_repo.to_path_buf();
+    old_path.push("index.old");
+    if !index_path.exists() && old_path.exists() {
+        // The last sync stopped halfway through swapping the index.
+        rename(&old_path, &index_path).unwrap();
+    }
+    let mut repo_meta = read_repo_meta(path_to_repo);
+    match repo_meta.mirror_of.as_ref() {
+        Some(of) => assert!(of == upstream, "{:?} is a mirror of {}", path_to_repo, of),
+        None => assert!(
+            artifact_cids(&index_path).is_empty(),
+            "{:?} has packages of its own and cannot become a mirror",
+            path_to_repo
+        ),
+    }
+    if !daemon_reachable(path_to_repo) {
+        start_daemon(path_to_repo);
+    }
+
+    let cid = ipfs(path_to_repo, &format!("name resolve /ipns/{}", upstream));
+    let cid = cid.trim_start_matches("/ipfs/");
+    if read_published(path_to_repo).is_some_and(|p| p.upstream_cid.as_deref() == Some(cid)) {
+        println!("The mirror of {} is up to date.", upstream);
+        return;
+    }
+    let mut tmp_path = path_to_repo.to_path_buf();
+    tmp_path.push("mirror.tmp");
+    if tmp_path.exists() {
+        remove_dir_all(&tmp_path).unwrap();
+    }
+    println!("Fetching the index of {}...", upstream);
+    ipfs(
+        path_to_repo,
+        &format!("get -o {} /ipfs/{}", tmp_path.to_str().unwrap(), cid),
+    );
+    let mut moved_path = tmp_path.clone();
+    moved_path.push("moved.toml");
+    if let Ok(moved) = read_to_string(&moved_path) {
+        remove_dir_all(&tmp_path).unwrap();
+        let moved: crate::index::Moved = toml::from_str(&moved).unwrap();
+        panic!("{} has moved to {}, mirror that instead", upstream, moved.to);
+    }
+
+    let manifest = crate::index::read_manifest(&tmp_path);
+    if let Some(local) = crate::index::read_manifest(&index_path) {
+        let generation = manifest.as_ref().map_or(0, |m| m.generation);
+        if generation < local.generation {
+            remove_dir_all(&tmp_path).unwrap();
+            panic!(
+                "{} publishes generation {}, older than generation {} in the mirror. Refusing to roll back.",
+                upstream, generation, local.generation
+            );
+        }
+    }
+    match (crate::index::read_signature(&tmp_path), manifest.as_ref()) {
+        (Some(signature), Some(manifest)) => {
+            let verify = |signature: &crate::index::Signature, path: &std::path::Path| {
+                let verified: serde_json::Value = serde_json::from_str(&ipfs(
+                    path_to_repo,
+                    &format!(
+                        "key verify --enc=json --key={} --signature={} {}",
+                        signature.key,
+                        signature.signature,
+                        path.to_str().unwrap()
+                    ),
+                ))
+                .unwrap();
+                verified["SignatureValid"] == serde_json::Value::Bool(true)
+            };
+            let mut manifest_path = tmp_path.clone();
+            manifest_path.push("manifest.toml");
+            let checked = crate::index::signed_maintainers(&tmp_path, upstream, &verify)
+                .and_then(|maintainers| {
+                    if signature.key != upstream
+                        && !maintainers.maintainers.values().any(|k| *k == signature.key)
+                    {
+                        return Err("it is not signed by one of its maintainers".to_string());
+                    }
+                    if !verify(&signature, &manifest_path) {
+                        return Err("its signature is not valid".to_string());
+                    }
+                    crate::index::check_digests(&tmp_path, manifest, "meta.toml", &sha256_file)
+                });
+            if let Err(e) = checked {
+                remove_dir_all(&tmp_path).unwrap();
+                panic!("Refusing the index published by {}: {}", upstream, e);
+            }
+        }
+        _ => println!("warning: the index published by {} is not signed", upstream),
+    }
+
+    let cids = artifact_cids(&tmp_path);
+    println!("Pinning {} artifacts...", cids.len());
+    for artifact in cids.iter() {
+        ipfs(path_to_repo, &format!("pin add {}", artifact));
+    }
+
+    repo_meta.mirror_of = Some(upstream.to_string());
+    let mut meta_path = tmp_path.clone();
+    meta_path.push("meta.toml");
+    // The manifest has the digest of upstream's meta.toml, which clients
+    // check against this copy.
+    let mut upstream_meta_path = tmp_path.clone();
+    upstream_meta_path.push("upstream.meta.toml");
+    copy(&meta_path, &upstream_meta_path).unwrap();
+    write(&meta_path, toml::to_string(&repo_meta).unwrap()).unwrap();
+    // Move the old index aside before putting the new one in its place, so
+    // there is always a whole index to go back to.
+    if old_path.exists() {
+        remove_dir_all(&old_path).unwrap();
+    }
+    if index_path.exists() {
+        rename(&index_path, &old_path).unwrap();
+    }
+    rename(&tmp_path, &index_path).unwrap();
+    if old_path.exists() {
+        remove_dir_all(&old_path).unwrap();
+    }
+
+    let hash = ipfs(
+        path_to_repo,
+        &format!("add --cid-version 1 -rQ {}", index_path.to_str().unwrap()),
+    );
+    println!("Publishing to ipfs...");
+    ipfs_name_publish(
+        path_to_repo,
+        &repo_meta.key,
+        &hash,
+        &read_repo_config(path_to_repo).publish_lifetime,
+    );
+    write_published(
+        path_to_repo,
+        &Published {
+            cid: hash,
+            fingerprint: fingerprint(&index_path),
+            upstream_cid: Some(cid.to_string()),
+            moved: published_moved(path_to_repo),
+        },
+    );
+    match manifest {
+        Some(manifest) => println!(
+            "Mirrored generation {} of {} at {}.",
+            manifest.generation, upstream, repo_meta.address
+        ),
+        None => println!("Mirrored {} at {}.", upstream, repo_meta.address),
+    }
+    println!(
+        "Clients add it with sps add-repo {} --mirror-of {}.",
+        repo_meta.address, upstream
+    );
+}
+
+/// Push the index to the repo's node, sign it and publish it.
+fn push(path_to_repo: &std::path::Path, valid_days: Option<u64>) {
+    let mut repo_index_path = path_to_repo.to_path_buf();
+    repo_index_path.push("index");
+    let meta_data = read_repo_meta(path_to_repo);
+    assert!(
+        meta_data.mirror_of.is_none(),
+        "{:?} is a mirror of {}. Run sps repository mirror to update it instead.",
+        path_to_repo,
+        meta_data.mirror_of.as_ref().unwrap_or(&String::new())
+    );
+
+    if !daemon_reachable(path_to_repo) {
+        start_daemon(path_to_repo);
+    }
+    assert!(
+        ipfs(path_to_repo, "key list").lines().any(|k| k == meta_data.key),
+        "The node has no key {}. Only the node holding the repo's key can push it, a maintainer's key just signs the index there.",
+        meta_data.key
+    );
+    let repo_config = read_repo_config(path_to_repo);
+    let valid_days = valid_days.or(repo_config.valid_days);
+    let signing_key = match repo_config.signing_key.as_ref() {
+        Some(key_path) => import_signing_key(path_to_repo, key_path),
+        None => meta_data.key.clone(),
+    };
+    let hash = push_index(path_to_repo, &repo_index_path, valid_days, &signing_key);
+
+    println!("Publishing to ipfs...");
+    let pub_hash = ipfs_name_publish(
+        path_to_repo,
+        &meta_data.key,
+        &hash,
+        &repo_config.publish_lifetime,
+    );
+    write_published(
+        path_to_repo,
+        &Published {
+            cid: hash.clone(),
+            fingerprint: fingerprint(&repo_index_path),
+            upstream_cid: None,
+            moved: published_moved(path_to_repo),
+        },
+    );
+
+    println!(
+        "here's the published hash, {} . Here's the reference hash, {} .",
+        &pub_hash, &meta_data.address
+    );
+}
+
+/// The name of the key in the node for the key file `key_path`, importing it
+/// the first time.
+fn import_signing_key(path_to_repo: &std::path::Path, key_path: &std::path::Path) -> String {
+    let name = format!(
+        "sps-signing-{}",
+        key_path.file_stem().unwrap().to_str().unwrap()
+    );
+    if !ipfs(path_to_repo, "key list").lines().any(|k| k == name) {
+        ipfs(
+            path_to_repo,
+            &format!("key import {} {}", name, key_path.to_str().unwrap()),
+        );
+    }
+    name
+}
+
+/// Sign `file_name` in `dir_path` with the node's key `key_name`, writing
+/// the signature next to it as `signature_name`. Returns the id of the key.
+fn sign(
+    path_to_repo: &std::path::Path,
+    dir_path: &std::path::Path,
+    file_name: &str,
+    signature_name: &str,
+    key_name: &str,
+) -> String {
+    #[derive(Deserialize)]
+    struct SignedKey {
+        #[serde(rename = "Id")]
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct Signed {
+        #[serde(rename = "Key")]
+        key: SignedKey,
+        #[serde(rename = "Signature")]
+        signature: String,
+    }
+    let mut path = dir_path.to_path_buf();
+    path.push(file_name);
+    let signed: Signed = serde_json::from_str(&ipfs(
+        path_to_repo,
+        &format!("key sign --enc=json --key={} {}", key_name, path.to_str().unwrap()),
+    ))
+    .expect("Could not read the output of ipfs key sign");
+    path.pop();
+    path.push(signature_name);
+    let signature = crate::index::Signature {
+        key: signed.key.id,
+        signature: signed.signature,
+    };
+    std::fs::write(&path, toml::to_string(&signature).unwrap()).unwrap();
+    signature.key
+}
+
+/// Per package CIDs from earlier pushes, kept in push_cache.toml in the
+/// repo root, so packages that did not change are not added again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PushCache {
+    #[serde(default)]
+    packages: std::collections::BTreeMap<String, CachedPackage>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedPackage {
+    /// Hash of the paths, sizes and modification times of every file.
+    fingerprint: String,
+    cid: String,
+    /// CID of each `<major>/<version>` directory, by version.
+    versions: std::collections::BTreeMap<String, String>,
+}
+
+fn read_push_cache(path_to_repo: &std::path::Path) -> PushCache {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("push_cache.toml");
+    std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| toml::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn write_push_cache(path_to_repo: &std::path::Path, cache: &PushCache) {
+    let mut path = path_to_repo.to_path_buf();
+    path.push("push_cache.toml");
+    std::fs::write(&path, toml::to_string(cache).unwrap()).unwrap();
+}
+
+/// Cheap stand-in for hashing a whole directory: the sha256 of the path,
+/// size and modification time of every file. It is kept in push_cache.toml
+/// and published.toml, so it has to come out the same across builds of sps.
+fn fingerprint(dir_path: &std::path::Path) -> String {
+    let mut files = fs_extra::dir::get_dir_content(dir_path).unwrap().files;
+    files.sort();
+    let mut listing = String::new();
+    for file in files {
+        let meta = std::fs::metadata(&file).unwrap();
+        let modified = meta
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        listing.push_str(&format!(
+            "{}\t{}\t{}.{:09}\n",
+            file,
+            meta.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        ));
+    }
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg("sha256sum")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to execute process");
+    use std::io::Write;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(listing.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "Could not run sha256sum");
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+/// The node keeps a copy of the index in its files API (MFS) under this path.
+const MFS_INDEX: &str = "/index";
+
+/// Bring the node's copy of the index up to date with `index_path`, write
+/// the next manifest, and return the CID of the index root. Only packages
+/// whose files changed since the last push are added again.
+fn push_index(
+    path_to_repo: &std::path::Path,
+    index_path: &std::path::Path,
+    valid_days: Option<u64>,
+    signing_key: &str,
+) -> String {
+    use std::fs::*;
+    let mut cache = read_push_cache(path_to_repo);
+    if try_ipfs(path_to_repo, &format!("files stat --hash {}", MFS_INDEX)).is_none() {
+        cache = PushCache::default();
+    }
+    ipfs(
+        path_to_repo,
+        &format!("files mkdir -p --cid-version 1 {}/pkgs", MFS_INDEX),
+    );
+
+    let mut pkgs_path = index_path.to_path_buf();
+    pkgs_path.push("pkgs");
+    let local: Vec<String> = match read_dir(&pkgs_path) {
+        Ok(entries) => entries
+            .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let pushed = ipfs_files_ls(path_to_repo, &format!("{}/pkgs", MFS_INDEX));
+    for name in pushed.iter().filter(|n| !local.contains(n)) {
+        ipfs(path_to_repo, &format!("files rm -r {}/pkgs/{}", MFS_INDEX, name));
+        cache.packages.remove(name);
+    }
+
+    for name in local.iter() {
+        let mut pkg_path = pkgs_path.clone();
+        pkg_path.push(name);
+        let fingerprint = fingerprint(&pkg_path);
+        if pushed.contains(name)
+            && cache
+                .packages
+                .get(name)
+                .is_some_and(|c| c.fingerprint == fingerprint)
+        {
+            continue;
+        }
+        println!("Adding {}...", name);
+        let mut package = CachedPackage {
+            fingerprint,
+            cid: String::new(),
+            versions: std::collections::BTreeMap::new(),
+        };
+        // Lines look like "added <cid> <name>/<major>/<version>/...".
+        let added = ipfs(
+            path_to_repo,
+            &format!("add --cid-version 1 -r {}", pkg_path.to_str().unwrap()),
+        );
+        for line in added.lines() {
+            let mut words = line.split_whitespace().skip(1);
+            let (cid, path) = (words.next().unwrap(), words.next().unwrap());
+            let parts: Vec<&str> = path.split('/').collect();
+            match parts.len() {
+                1 => package.cid = cid.to_string(),
+                3 => {
+                    package.versions.insert(parts[2].to_string(), cid.to_string());
+                }
+                _ => (),
+            }
+        }
+        if pushed.contains(name) {
+            ipfs(path_to_repo, &format!("files rm -r {}/pkgs/{}", MFS_INDEX, name));
+        }
+        ipfs(
+            path_to_repo,
+            &format!("files cp /ipfs/{} {}/pkgs/{}", package.cid, MFS_INDEX, name),
+        );
+        cache.packages.insert(name.clone(), package);
+    }
+    write_push_cache(path_to_repo, &cache);
+
+    // Every package under pkgs is copied in from a fresh add and pkgs itself
+    // is made with --cid-version 1, so it hashes as adding the directory
+    // would. Clients check it that way against the manifest.
+    let pkgs = if cache.packages.is_empty() {
+        None
+    } else {
+        Some(ipfs(path_to_repo, &format!("files stat --hash {}/pkgs", MFS_INDEX)))
+    };
+    // The list of maintainers is vouched for by the repo's own key, whoever
+    // signs the manifest.
+    let repo_meta = read_repo_meta(path_to_repo);
+    let maintainers = crate::index::read_maintainers(index_path).maintainers;
+    let mut maintainers_path = index_path.to_path_buf();
+    maintainers_path.push("maintainers.toml");
+    if maintainers_path.exists() {
+        sign(
+            path_to_repo,
+            index_path,
+            "maintainers.toml",
+            "maintainers.signature.toml",
+            &repo_meta.key,
+        );
+    }
+    let mut digests = std::collections::BTreeMap::new();
+    for name in crate::index::DIGESTED.iter() {
+        let mut path = index_path.to_path_buf();
+        path.push(name);
+        if path.exists() {
+            digests.insert(name.to_string(), sha256_file(&path));
+        }
+    }
+    write_manifest(index_path, &cache, pkgs, digests, valid_days, maintainers.clone());
+    let signer = sign(
+        path_to_repo,
+        index_path,
+        "manifest.toml",
+        "signature.toml",
+        signing_key,
+    );
+    assert!(
+        signer == repo_meta.address || maintainers.values().any(|k| *k == signer),
+        "The index was signed with {}, which is neither the repo's key nor a maintainer's",
+        signer
+    );
+
+    // The files next to pkgs are small, so they are simply replaced.
+    let pushed = ipfs_files_ls(path_to_repo, MFS_INDEX);
+    let mut local = Vec::new();
+    for entry in read_dir(index_path).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_str().unwrap().to_string();
+        if name == "pkgs" {
+            continue;
+        }
+        let cid = ipfs(
+            path_to_repo,
+            &format!(
+                "add --cid-version 1 -rQ {}",
+                entry.path().to_str().unwrap()
+            ),
+        );
+        if pushed.contains(&name) {
+            ipfs(path_to_repo, &format!("files rm -r {}/{}", MFS_INDEX, name));
+        }
+        ipfs(
+            path_to_repo,
+            &format!("files cp /ipfs/{} {}/{}", cid, MFS_INDEX, name),
+        );
+        local.push(name);
+    }
+    for name in pushed.iter().filter(|n| *n != "pkgs" && !local.contains(n)) {
+        ipfs(path_to_repo, &format!("files rm -r {}/{}", MFS_INDEX, name));
+    }
+    ipfs(path_to_repo, &format!("files stat --hash {}", MFS_INDEX))
+}
+
+/// Regenerate `manifest.toml` in the index with the next generation number.
+fn write_manifest(
+    index_path: &std::path::Path,
+    cache: &PushCache,
+    pkgs: Option<String>,
+    digests: std::collections::BTreeMap<String, String>,
+    valid_days: Option<u64>,
+    maintainers: std::collections::BTreeMap<String, String>,
+) {
+    use crate::index::{now, Manifest, MANIFEST_FORMAT};
+    let generation = crate::index::read_manifest(index_path).map_or(0, |m| m.generation) + 1;
+    let manifest = Manifest {
+        format: MANIFEST_FORMAT,
+        generation,
+        published: now(),
+        expires: valid_days.map(|days| now() + days * 24 * 3600),
+        pkgs,
+        digests,
+        packages: cache
+            .packages
+            .iter()
+            .map(|(name, p)| (name.clone(), p.versions.clone()))
+            .collect(),
+        maintainers,
+    };
+
+    let mut manifest_path = index_path.to_path_buf();
+    manifest_path.push("manifest.toml");
+    std::fs::write(&manifest_path, toml::to_string(&manifest).unwrap()).unwrap();
+    println!("Wrote generation {} of the manifest.", generation);
+}
+
+/// Every artifact CID listed in a per-version `index` file of an index.
+fn artifact_cids(index_path: &std::path::Path) -> std::collections::BTreeSet<String> {
+    let mut cids = std::collections::BTreeSet::new();
+    let mut pkgs_path = index_path.to_path_buf();
+    pkgs_path.push("pkgs");
+    if !pkgs_path.is_dir() {
+        return cids;
+    }
+    for file in fs_extra::dir::get_dir_content(&pkgs_path).unwrap().files {
+        let path = std::path::Path::new(&file);
+        if path.file_name().unwrap() != "index" {
+            continue;
+        }
+        let index = std::fs::read_to_string(path)
+            .unwrap()
+            .parse::<toml::Value>()
+            .unwrap();
+        for cid in index.as_table().unwrap().values() {
+            cids.insert(cid.as_str().unwrap().to_string());
+        }
+    }
+    cids
+}
+
+/// What gc keeps pinned: the artifacts of the index and, once the repo has
+/// been pushed, the published index fetched to `published_path` and what it
+/// refers to, since clients keep installing from it until the next push, and
+/// the pointers left at the old addresses.
+fn gc_keep(
+    index_path: &std::path::Path,
+    published: Option<&Published>,
+    published_path: &std::path::Path,
+) -> std::collections::BTreeSet<String> {
+    let mut keep = artifact_cids(index_path);
+    if let Some(published) = published {
+        keep.extend(artifact_cids(published_path));
+        keep.insert(published.cid.clone());
+        keep.extend(published.moved.iter().cloned());
+    }
+    keep
+}
+
+/// The `index/pkgs/<name>/<major>/<version>` directory of a published version.
+/// `version` may name a revision, like 1.2.0_1.
+fn published_version_path(path_to_repo: &std::path::Path, name: &str, version: &str) -> PathBuf {
+    let (version, revision) =
+        crate::index::parse_display_version(version).expect("Invalid version string");
+    let mut path = path_to_repo.to_path_buf();
+    path.push("index");
+    path.push("pkgs");
+    path.push(name);
+    path.push(format!("{}", version.major));
+    path.push(crate::index::version_dir(&version, revision));
+    assert!(
+        path.is_dir(),
+        "{} {} is not in this repository",
+        name,
+        crate::index::display_version(&version, revision)
+    );
+    path
+}
+
+/// Set `key = reason` in the meta.toml of a published version, or remove
+/// it when `undo` is set.
+fn mark_version(version_path: &std::path::Path, key: &str, reason: Option<String>, undo: bool) {
+    let mut meta_path = version_path.to_path_buf();
+    meta_path.push("meta.toml");
+    let mut meta = std::fs::read_to_string(&meta_path)
+        .unwrap()
+        .parse::<toml::Value>()
+        .unwrap();
+    let table = meta.as_table_mut().unwrap();
+    if undo {
+        assert!(table.remove(key).is_some(), "The version is not {}", key);
+    } else {
+        table.insert(
+            key.to_string(),
+            toml::Value::String(reason.unwrap_or_default()),
+        );
+    }
+    std::fs::write(&meta_path, toml::to_string(&meta).unwrap()).unwrap();
+}
+
+/// Sources unpacked from a git repository or tarball for Add.
+struct Source {
+    /// Removed when dropped, whether or not the package was added.
+    tmp_path: PathBuf,
+    /// The project inside `tmp_path`.
+    proj_path: PathBuf,
+    /// What goes in the `[source]` table of the stored meta.toml.
+    table: toml::value::Table,
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.tmp_path);
+    }
+}
+
+/// Where Add builds packages before moving them into the index.
+struct Staging {
+    /// Removed when dropped, with whatever a failed build left in it.
+    path: PathBuf,
+}
+
+impl Staging {
+    fn new(path_to_repo: &std::path::Path) -> Staging {
+        use uuid::Uuid;
+        let mut path = path_to_repo.to_path_buf();
+        path.push(format!(
+            ".build.{}.tmp",
+            Uuid::new_v4()
+                .to_simple()
+                .encode_lower(&mut Uuid::encode_buffer())
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Staging { path }
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Unpack `path_to_proj` next to the repo if it is a tarball, or a git
+/// repository to package at `rev`. None if it is a plain project directory.
+fn unpack_source(
+    path_to_repo: &std::path::Path,
+    path_to_proj: &std::path::Path,
+    rev: Option<&str>,
+) -> Option<Source> {
+    use toml::Value;
+    if rev.is_none() && !path_to_proj.is_file() {
+        return None;
+    }
+    use uuid::Uuid;
+    let mut tmp_path = path_to_repo.to_path_buf();
+    tmp_path.push(format!(
+        ".add.{}.tmp",
+        Uuid::new_v4()
+            .to_simple()
+            .encode_lower(&mut Uuid::encode_buffer())
+    ));
+    std::fs::create_dir_all(&tmp_path).unwrap();
+    // Built right away so a failure below removes the directory too.
+    let mut source = Source {
+        tmp_path: tmp_path.clone(),
+        proj_path: tmp_path.clone(),
+        table: toml::value::Table::new(),
+    };
+    let tmp = tmp_path.to_str().unwrap();
+
+    match rev {
+        Some(rev) => {
+            let proj = path_to_proj.to_str().unwrap();
+            let commit = sh_output(&format!(
+                "git -C {} rev-parse --verify {}^{{commit}}",
+                proj, rev
+            ))
+            .unwrap_or_else(|| panic!("{} is not a commit in the git repository {}", rev, proj));
+            let archive = format!("{}.tar", tmp);
+            sh_output(&format!(
+                "git -C {} archive --format=tar -o {} {} && bsdtar -xf {} -C {}",
+                proj, archive, commit, archive, tmp
+            ))
+            .expect("Could not export the sources from git");
+            source.table.insert("rev".to_string(), Value::String(commit));
+            source.table.insert(
+                "sha256".to_string(),
+                Value::String(sha256_file(std::path::Path::new(&archive))),
+            );
+            std::fs::remove_file(&archive).unwrap();
+        }
+        None => {
+            sh_output(&format!(
+                "bsdtar -xf {} -C {}",
+                path_to_proj.to_str().unwrap(),
+                tmp
+            ))
+            .unwrap_or_else(|| panic!("Could not unpack {:?}", path_to_proj));
+            source.table.insert(
+                "file".to_string(),
+                Value::String(path_to_proj.file_name().unwrap().to_str().unwrap().to_string()),
+            );
+            source.table.insert("sha256".to_string(), Value::String(sha256_file(path_to_proj)));
+            // Source tarballs usually hold a single <name>-<version> directory.
+            let entries: Vec<_> = std::fs::read_dir(&tmp_path)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            if entries.len() == 1 && entries[0].is_dir() {
+                source.proj_path = entries[0].clone();
+            }
+        }
+    }
+    Some(source)
+}
+
+pub fn sha256_file(path: &std::path::Path) -> String {
+    sh_output(&format!("sha256sum {}", path.to_str().unwrap()))
+        .expect("Could not run sha256sum")
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+/// Run `command` in sh and return what it printed, minus the trailing
+/// newline, or None if it failed.
+fn sh_output(command: &str) -> Option<String> {
+    let mut output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    if !output.status.success() {
+        return None;
+    }
+    if !output.stdout.is_empty() {
+        output.stdout.truncate(output.stdout.len() - 1);
+    }
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+/// Version control directories, never packaged.
+const VCS_DIRS: [&str; 4] = [".git", ".hg", ".svn", ".bzr"];
+
+/// Editor and desktop leftovers, never packaged.
+const JUNK: [&str; 5] = ["*~", "*.swp", "*.swo", ".#*", ".DS_Store"];
+
+/// The files of the project to package, relative to `proj_path`. Skips
+/// version control directories, editor leftovers, whatever the .gitignore
+/// and .spsignore files of the project rule out, and the files outside the
+/// `include` or inside the `exclude` lists of meta.toml, both in .gitignore
+/// syntax.
+fn project_files(proj_path: &std::path::Path, proj_meta: &toml::Value) -> Vec<PathBuf> {
+    let rules = |key: &str| {
+        proj_meta
+            .get(key)
+            .map(|patterns| file_patterns(proj_path, patterns, key))
+    };
+    let include = rules("include");
+    let exclude = rules("exclude");
+    let junk = file_patterns(proj_path, &toml::Value::from(JUNK.to_vec()), "junk");
+
+    let mut files = Vec::new();
+    // Only the project's own .gitignore files count, so what gets packaged
+    // does not depend on the machine or on whether it is a git checkout.
+    for entry in ignore::WalkBuilder::new(proj_path)
+        .standard_filters(false)
+        .git_ignore(true)
+        .require_git(false)
+        .add_custom_ignore_filename(".spsignore")
+        .filter_entry(|e| !VCS_DIRS.iter().any(|d| e.file_name() == *d))
+        .build()
+    {
+        let entry = entry.unwrap();
+        if entry.file_type().is_none_or(|t| t.is_dir()) {
+            continue;
+        }
+        let path = entry.path().strip_prefix(proj_path).unwrap().to_path_buf();
+        // meta.toml and config.toml go next to the variants, sps_build.sh
+        // is written out for each of them and the hooks always go in.
+        if ["meta.toml", "config.toml", "sps_build.sh", ".spsignore"]
+            .iter()
+            .chain(HOOKS.iter())
+            .any(|f| path == std::path::Path::new(f))
+        {
+            continue;
+        }
+        if junk.matched_path_or_any_parents(&path, false).is_ignore() {
+            continue;
+        }
+        if include
+            .as_ref()
+            .is_some_and(|i| !i.matched_path_or_any_parents(&path, false).is_ignore())
+        {
+            continue;
+        }
+        if exclude
+            .as_ref()
+            .is_some_and(|e| e.matched_path_or_any_parents(&path, false).is_ignore())
+        {
+            continue;
+        }
+        files.push(path);
+    }
+    files.sort();
+    files
+}
+
+/// The name of the shell variable holding the directory a build script
+/// puts the files of subpackage `name` in, less the SPS_SUBPACKAGE_DIR_.
+pub fn subpackage_var(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The `pkgs/<name>/<major>/<version>` directory to add a package to.
+/// Published versions never change, so it panics if the version is in the
+/// index already, unless `force` is set. Then the published directory comes
+/// second, to be replaced once the new one is built.
+fn claim_version_dir(
+    index_path: &std::path::Path,
+    name: &str,
+    version: &Version,
+    revision: u64,
+    force: bool,
+) -> (PathBuf, Option<PathBuf>) {
+    let mut dest_path = index_path.to_path_buf();
+    dest_path.push("pkgs");
+    dest_path.push(name);
+    dest_path.push(format!("{}", version.major));
+    // Build metadata does not count when ordering versions, so 1.0.0+a is
+    // published already if 1.0.0+b is.
+    let published = crate::index::revisions_of(index_path, name)
+        .into_iter()
+        .find(|(v, r)| v == version && *r == revision);
+    let replaced = published.map(|(version, revision)| {
+        let next = crate::index::newest_revision(index_path, name, &version).unwrap_or(0) + 1;
+        assert!(
+            force,
+            "{} {} is already published as revision {}, and published versions never change. Set revision = {} in meta.toml for the rebuild, or pass --force to replace it.",
+            name, version, revision, next
+        );
+        let mut published_path = dest_path.clone();
+        published_path.push(crate::index::version_dir(&version, revision));
+        published_path
+    });
+    dest_path.push(crate::index::version_dir(version, revision));
+    (dest_path, replaced)
+}
+
+/// Match paths relative to `root` against a meta.toml list of patterns in
+/// .gitignore syntax, called `what` in errors.
+pub fn file_patterns(
+    root: &std::path::Path,
+    patterns: &toml::Value,
+    what: &str,
+) -> ignore::gitignore::Gitignore {
+    let mut builder = ignore::gitignore::GitignoreBuilder::new(root);
+    for pattern in patterns
+        .as_array()
+        .unwrap_or_else(|| panic!("{} in meta.toml must be a list", what))
+        .iter()
+    {
+        builder
+            .add_line(None, pattern.as_str().unwrap())
+            .unwrap_or_else(|e| panic!("Bad pattern in {} in meta.toml: {}", what, e));
+    }
+    builder.build().unwrap()
+}
+
+fn copy_project(proj_path: &std::path::Path, out_path: &std::path::Path, files: &[PathBuf]) {
+    use std::fs::*;
+    for file in files.iter() {
+        let mut from = proj_path.to_path_buf();
+        from.push(file);
+        let mut to = out_path.to_path_buf();
+        to.push(file);
+        create_dir_all(to.parent().unwrap()).unwrap();
+        if symlink_metadata(&from).unwrap().file_type().is_symlink() {
+            std::os::unix::fs::symlink(read_link(&from).unwrap(), &to).unwrap();
+        } else {
+            copy(&from, &to).unwrap_or_else(|e| panic!("Could not copy {:?}: {}", from, e));
+        }
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"].iter() {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
+
+/// Why `name` cannot be a package name, if it cannot.
+fn check_package_name(name: &str) -> Option<String> {
+    if name.is_empty() {
+        return Some("The package name is empty".to_string());
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '+')
+        || name.starts_with('.')
+    {
+        return Some(format!(
+            "{} is not a valid package name, use letters, digits and - _ . + only",
+            name
+        ));
+    }
+    None
+}
+
+/// Scripts a project may have next to sps_build.sh, run by clients as
+/// packages are installed and removed.
+pub const HOOKS: [&str; 4] = [
+    "sps_pre_install.sh",
+    "sps_post_install.sh",
+    "sps_pre_remove.sh",
+    "sps_post_remove.sh",
+];
+
+/// Everything wrong with the project in `proj_path` that would stop it from
+/// being added to a repo. Empty if nothing is.
+pub fn lint_project(proj_path: &std::path::Path) -> Vec<String> {
+    use toml::Value;
+    let mut problems = Vec::new();
+    let read = |file: &str, problems: &mut Vec<String>| -> Option<Value> {
+        let mut path = proj_path.to_path_buf();
+        path.push(file);
+        match std::fs::read_to_string(&path) {
+            Ok(s) => match s.parse::<Value>() {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    problems.push(format!("{} is not valid toml: {}", file, e));
+                    None
+                }
+            },
+            Err(_) => {
+                problems.push(format!("{} is missing", file));
+                None
+            }
+        }
+    };
+    let strings = |value: Option<&Value>| -> Option<Vec<String>> {
+        value?
+            .as_array()?
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_string()))
+            .collect()
+    };
+
+    if let Some(meta) = read("meta.toml", &mut problems) {
+        match meta.get("name").map(|n| n.as_str()) {
+            Some(Some(name)) => problems.extend(check_package_name(name)),
+            Some(None) => problems.push("name in meta.toml must be a string".to_string()),
+            None => problems.push("meta.toml has no name".to_string()),
+        }
+        match meta.get("version").map(|v| v.as_str()) {
+            Some(Some(version)) => {
+                if let Err(e) = Version::parse(version) {
+                    problems.push(format!("{} in meta.toml is not a valid version: {}", version, e));
+                }
+            }
+            Some(None) => problems.push("version in meta.toml must be a string".to_string()),
+            None => problems.push("meta.toml has no version".to_string()),
+        }
+        if meta
+            .get("revision")
+            .is_some_and(|r| r.as_integer().is_none_or(|r| r < 0))
+        {
+            problems.push("revision in meta.toml must be a non-negative integer".to_string());
+        }
+        if meta.get("description").is_some_and(|d| !d.is_str()) {
+            problems.push("description in meta.toml must be a string".to_string());
+        }
+        for key in ["include", "exclude", "config_files"].iter() {
+            if meta.get(*key).is_some() && strings(meta.get(*key)).is_none() {
+                problems.push(format!("{} in meta.toml must list patterns as strings", key));
+            }
+        }
+        let check_dependencies = |meta: &Value, problems: &mut Vec<String>| {
+            match meta.get("dependencies").map(|d| d.as_table()) {
+                Some(Some(deps)) => {
+                    for (name, req) in deps.iter() {
+                        match req.as_str().map(VersionReq::parse) {
+                            Some(Ok(_)) => (),
+                            Some(Err(e)) => problems.push(format!(
+                                "The version requirement for dependency {} is not valid: {}",
+                                name, e
+                            )),
+                            None => problems.push(format!(
+                                "Dependency {} must be a version requirement string",
+                                name
+                            )),
+                        }
+                    }
+                }
+                Some(None) => {
+                    problems.push("dependencies in meta.toml must be a table".to_string())
+                }
+                None => (),
+            }
+        };
+        check_dependencies(&meta, &mut problems);
+        let check_provides = |meta: &Value, problems: &mut Vec<String>| {
+            let name = meta.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            match strings(meta.get("provides")) {
+                Some(provides) => {
+                    for provided in provides.iter() {
+                        problems.extend(check_package_name(provided));
+                        if provided == name {
+                            problems.push(format!("{} cannot provide itself", name));
+                        }
+                    }
+                }
+                None if meta.get("provides").is_some() => problems.push(format!(
+                    "provides of {} must list the names of virtual packages",
+                    name
+                )),
+                None => (),
+            }
+        };
+        check_provides(&meta, &mut problems);
+
+        let mut names = vec![meta.get("name").and_then(|n| n.as_str()).unwrap_or_default()];
+        match meta.get("subpackage").map(|s| s.as_array()) {
+            Some(Some(subpackages)) => {
+                for sub in subpackages.iter() {
+                    let name = match sub.get("name").and_then(|n| n.as_str()) {
+                        Some(name) => name,
+                        None => {
+                            problems.push("Every [[subpackage]] needs a name".to_string());
+                            continue;
+                        }
+                    };
+                    problems.extend(check_package_name(name));
+                    if names.contains(&name) {
+                        problems.push(format!("There is more than one package named {}", name));
+                    }
+                    names.push(name);
+                    if sub.get("description").is_some_and(|d| !d.is_str()) {
+                        problems.push(format!("The description of {} must be a string", name));
+                    }
+                    if strings(sub.get("files")).is_none() {
+                        problems.push(format!(
+                            "Subpackage {} needs files = [] listing the patterns of its files",
+                            name
+                        ));
+                    }
+                    check_dependencies(sub, &mut problems);
+                    check_provides(sub, &mut problems);
+                    if sub.get("config_files").is_some() && strings(sub.get("config_files")).is_none() {
+                        problems.push(format!(
+                            "config_files of {} must list patterns as strings",
+                            name
+                        ));
+                    }
+                }
+            }
+            Some(None) => problems.push("subpackages must be given as [[subpackage]]".to_string()),
+            None => (),
+        }
+    }
+
+    if let Some(conf) = read("config.toml", &mut problems) {
+        for key in ["flags", "archs", "enums"].iter() {
+            if strings(conf.get(*key)).is_none() {
+                problems.push(format!(
+                    "config.toml needs {} = [] listing strings, even if empty",
+                    key
+                ));
+            }
+        }
+        for e in strings(conf.get("enums")).unwrap_or_default() {
+            match strings(conf.get(&e)) {
+                Some(values) if values.is_empty() => {
+                    problems.push(format!("Enum {} in config.toml has no values", e))
+                }
+                Some(_) => (),
+                None => problems.push(format!(
+                    "Enum {} needs {} = [] listing its values in config.toml",
+                    e, e
+                )),
+            }
+        }
+    }
+
+    let mut build_path = proj_path.to_path_buf();
+    build_path.push("sps_build.sh");
+    if !build_path.is_file() {
+        problems.push("sps_build.sh is missing".to_string());
+    }
+    for script in std::iter::once(&"sps_build.sh").chain(HOOKS.iter()) {
+        let mut path = proj_path.to_path_buf();
+        path.push(script);
+        if !path.is_file() {
+            continue;
+        }
+        let output = std::process::Command::new("sh")
+            .arg("-n")
+            .arg(&path)
+            .output()
+            .expect("failed to execute process");
+        if !output.status.success() {
+            problems.push(format!(
+                "{} has a syntax error: {}",
+                script,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+    }
+    problems
+}
+
+/// The `[dependencies]` table of a meta.toml, as `name = "version requirement"`.
+pub fn read_dependencies(meta: &toml::Value) -> Vec<(String, VersionReq)> {
+    match meta.get("dependencies") {
+        None => Vec::new(),
+        Some(deps) => deps
+            .as_table()
+            .expect("dependencies in meta.toml must be a table")
+            .iter()
+            .map(|(name, req)| {
+                let req = req
+                    .as_str()
+                    .unwrap_or_else(|| panic!("Dependency {} must be a version string", name));
+                (
+                    name.clone(),
+                    VersionReq::parse(req).unwrap_or_else(|e| {
+                        panic!("Invalid version requirement for {}: {}", name, e)
+                    }),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// The virtual packages listed in `provides` of a meta.toml.
+pub fn read_provides(meta: &toml::Value) -> Vec<String> {
+    match meta.get("provides") {
+        None => Vec::new(),
+        Some(provides) => provides
+            .as_array()
+            .expect("provides in meta.toml must be a list")
+            .iter()
+            .map(|p| p.as_str().expect("provides in meta.toml must list names").to_string())
+            .collect(),
+    }
+}
+
+/// List `provider` in `pkgs/<name>/providers.toml` of the index.
+fn add_provider(index_path: &std::path::Path, name: &str, provider: &str) {
+    let mut providers = crate::index::read_providers(index_path, name);
+    if providers.providers.iter().any(|p| p == provider) {
+        return;
+    }
+    providers.providers.push(provider.to_string());
+    providers.providers.sort();
+    let mut path = index_path.to_path_buf();
+    path.push("pkgs");
+    path.push(name);
+    std::fs::create_dir_all(&path).unwrap();
+    path.push("providers.toml");
+    std::fs::write(&path, toml::to_string(&providers).unwrap()).unwrap();
+}
+
+/// Take `provider` out of `pkgs/<name>/providers.toml` of the index for each
+/// virtual package none of its remaining versions provides, removing the
+/// file when no provider is left.
+fn remove_provider(index_path: &std::path::Path, provider: &str) {
+    let mut still_provided = std::collections::BTreeSet::new();
+    for (version, revision) in crate::index::revisions_of(index_path, provider) {
+        let mut path = index_path.to_path_buf();
+        path.push("pkgs");
+        path.push(provider);
+        path.push(format!("{}", version.major));
+        path.push(crate::index::version_dir(&version, revision));
+        path.push("meta.toml");
+        let meta = std::fs::read_to_string(&path)
+            .unwrap()
+            .parse::<toml::Value>()
+            .unwrap();
+        still_provided.extend(read_provides(&meta));
+    }
+    for name in crate::index::package_versions(index_path).keys() {
+        let mut providers = crate::index::read_providers(index_path, name);
+        if still_provided.contains(name) || !providers.providers.iter().any(|p| p == provider) {
+            continue;
+        }
+        providers.providers.retain(|p| p != provider);
+        let mut path = index_path.to_path_buf();
+        path.push("pkgs");
+        path.push(name);
+        path.push("providers.toml");
+        println!("{} no longer provides {}.", provider, name);
+        if !providers.providers.is_empty() {
+            std::fs::write(&path, toml::to_string(&providers).unwrap()).unwrap();
+            continue;
+        }
+        std::fs::remove_file(&path).unwrap();
+        path.pop();
+        if std::fs::read_dir(&path).unwrap().next().is_none() {
+            std::fs::remove_dir(&path).unwrap();
+        }
+    }
+}
+
+pub fn read_project_config(proj_conf_path: &std::path::Path) -> ProjectConfig {
+    use std::fs::*;
+    use toml::Value;
+    let proj_conf = read_to_string(proj_conf_path)
+        .unwrap()
+        .parse::<Value>()
+        .unwrap();
+    assert!(
+        proj_conf.as_table().unwrap().contains_key("enums"),
+        "Found no enums = [] in config.toml"
+    );
+    assert!(
+        proj_conf.as_table().unwrap().contains_key("archs"),
+        "Found no archs = [] in config.toml"
+    );
+    assert!(
+        proj_conf.as_table().unwrap().contains_key("flags"),
+        "Found no flags = [] in config.toml"
+    );
+    let mut enums = Vec::new();
+    for e in proj_conf["enums"]
+        .as_array()
+        .expect("Found no enums = [] in config.toml")
+        .iter()
+        .map(|x| x.as_str().unwrap().to_string())
+    {
+        assert!(
+            proj_conf.as_table().unwrap().contains_key(&e),
+            format!("There was no enum name {} in config.toml", &e),
+        );
+        enums.push((
+            e.clone(),
+            proj_conf[&e]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x.as_str().unwrap().to_string())
+                .collect(),
+        ));
+    }
+
+    ProjectConfig {
+        flags: proj_conf["flags"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap().to_string())
+            .collect(),
+        archs: proj_conf["archs"]
+            .as_array()
+            .expect("Found no archs = [] in config.toml")
+            .iter()
+            .map(|x| x.as_str().unwrap().to_string())
+            .collect(),
+        enums: enums,
+    }
+}
+
+/// Every combination of flag, arch and enum values in `configdata`. A
+/// variant's number in the index is its position in this list.
+pub fn build_options(configdata: &ProjectConfig) -> Vec<Vec<(String, String)>> {
+    let mut options: Vec<(String, Vec<String>)> = Vec::new();
+    for flag in configdata.flags.iter() {
+        options.push((flag.to_string(), vec!["".to_owned(), "1".to_owned()]));
+    }
+
+    if configdata.archs.len() > 0 {
+        options.push(("archs".to_owned(), configdata.archs.clone()));
+    }
+    options.extend_from_slice(&configdata.enums);
+    let mut option_counts = Vec::new();
+    for x in options.iter() {
+        option_counts.push(x.1.len());
+    }
+
+    for o in options.iter() {
+        assert!(
+            o.1.len() > 0,
+            format!("Enum {} must have atleast one possible value.", &o.0)
+        );
+    }
+    let mut current_option = vec![0; options.len()];
+
+    let mut all_options = Vec::new();
+    all_options.push(current_option.clone());
+    let mut digit = 0;
+    loop {
+        if digit >= current_option.len() {
+            break;
+        }
+        current_option[digit] += 1;
+        if current_option[digit] >= option_counts[digit] {
+            current_option[digit] = 0;
+            digit += 1;
+        } else {
+            digit = 0;
+            all_options.push(current_option.clone());
+        }
+    }
+    all_options
+        .iter()
+        .map(|ao| {
+            ao.iter()
+                .enumerate()
+                .map(|(i, v)| (options[i].0.clone(), options[i].1[*v].clone()))
+                .collect()
+        })
+        .collect()
+}
+
+fn tar_and_zstd_dir(dir_path: &std::path::Path, level: u32) {
     let absolute_path = dir_path.canonicalize().unwrap();
     let dir_name = absolute_path.file_name().unwrap().to_str().unwrap();
     let mut output = std::process::Command::new("sh")
         .arg("-c")
         .arg(format!(
-            "cd {}/.. && bsdtar --format=pax -cf {}.tar {} && zstd --rm -f {}.tar",
+            "cd {}/.. && bsdtar --format=pax -cf {}.tar {} && zstd --ultra -{} --rm -f {}.tar",
             absolute_path.to_str().unwrap(),
             dir_name,
             dir_name,
+            level,
             dir_name
         ))
         .spawn()
@@ -448,30 +2826,157 @@ fn ipfs_add_and_rm(repo_path: &std::path::Path, item_path: &std::path::Path) ->
     }
     String::from_utf8(output.stdout).unwrap()
 }
-fn ipfs_add_recursive(repo_path: &std::path::Path, item_path: &std::path::Path) -> String {
-    let mut output = std::process::Command::new("sh")
+/// The port of the repo node's API, read from its ipfs config.
+fn ipfs_api_port(repo_path: &std::path::Path) -> u16 {
+    let output = std::process::Command::new("sh")
         .arg("-c")
         .arg(format!(
-            "IPFS_PATH={}/ipfs ipfs add --cid-version 1 -rQ {}",
+            "IPFS_PATH={}/ipfs ipfs config Addresses.API",
             repo_path.to_str().unwrap(),
-            item_path.to_str().unwrap(),
         ))
         .output()
         .expect("failed to execute process");
     use std::io::Write;
     std::io::stderr().write_all(&output.stderr).unwrap();
     assert!(output.status.success());
-    if output.stdout.len() > 0 {
-        output.stdout.truncate(output.stdout.len() - 1);
+    // The address looks like /ip4/127.0.0.1/tcp/16461
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .rsplit('/')
+        .next()
+        .unwrap()
+        .parse()
+        .expect("Could not read the API port from the ipfs config")
+}
+
+fn daemon_reachable(repo_path: &std::path::Path) -> bool {
+    let address = std::net::SocketAddr::from(([127, 0, 0, 1], ipfs_api_port(repo_path)));
+    std::net::TcpStream::connect_timeout(&address, std::time::Duration::from_secs(1)).is_ok()
+}
+
+fn pid_path(repo_path: &std::path::Path) -> PathBuf {
+    let mut path = repo_path.to_path_buf();
+    path.push("daemon.pid");
+    path
+}
+
+/// The pid of the daemon started by `start_daemon`, if it is still alive.
+fn daemon_pid(repo_path: &std::path::Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(pid_path(repo_path))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    if is_repo_daemon(repo_path, pid) {
+        Some(pid)
+    } else {
+        None
     }
-    String::from_utf8(output.stdout).unwrap()
 }
-fn ipfs_name_publish(repo_path: &std::path::Path, key_name: &str, hash: &str) -> String {
+
+/// IPFS_PATH of the repo's background daemon. Canonical, so the daemon is
+/// recognized however the repo was named when it was started.
+fn daemon_ipfs_path(repo_path: &std::path::Path) -> String {
+    let mut path = repo_path.canonicalize().unwrap();
+    path.push("ipfs");
+    path.to_str().unwrap().to_string()
+}
+
+/// Whether process `pid` is an ipfs daemon for this repo's node, and not
+/// whatever got the pid after the daemon exited.
+fn is_repo_daemon(repo_path: &std::path::Path, pid: u32) -> bool {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args: Vec<&[u8]> = cmdline.split(|b| *b == 0).collect();
+    let is_daemon = args.iter().any(|a| a.ends_with(b"ipfs")) && args.contains(&&b"daemon"[..]);
+    let environ = std::fs::read(format!("/proc/{}/environ", pid)).unwrap_or_default();
+    let ipfs_path = format!("IPFS_PATH={}", daemon_ipfs_path(repo_path));
+    is_daemon && environ.split(|b| *b == 0).any(|v| v == ipfs_path.as_bytes())
+}
+
+/// Run the repo's daemon in the background, logging to daemon.log, and
+/// wait until its API port accepts connections.
+#[allow(clippy::zombie_processes)] // The daemon is meant to outlive sps.
+fn start_daemon(repo_path: &std::path::Path) {
+    if let Some(pid) = daemon_pid(repo_path) {
+        println!("The daemon is already running with pid {}.", pid);
+        return;
+    }
+    let mut log_path = repo_path.to_path_buf();
+    log_path.push("daemon.log");
+    let log = std::fs::File::create(&log_path).unwrap();
+    use std::os::unix::process::CommandExt;
+    let mut child = std::process::Command::new("ipfs")
+        .arg("daemon")
+        .env("IPFS_PATH", daemon_ipfs_path(repo_path))
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone().unwrap())
+        .stderr(log)
+        // Its own process group, so a ^C meant for sps does not reach it.
+        .process_group(0)
+        .spawn()
+        .expect("failed to execute process");
+    std::fs::write(pid_path(repo_path), format!("{}\n", child.id())).unwrap();
+
+    println!("Starting the daemon...");
+    for _ in 0..600 {
+        if daemon_reachable(repo_path) {
+            println!(
+                "The daemon is running with pid {} on port {}.",
+                child.id(),
+                ipfs_api_port(repo_path)
+            );
+            return;
+        }
+        if let Some(status) = child.try_wait().unwrap() {
+            std::fs::remove_file(pid_path(repo_path)).unwrap();
+            panic!("The daemon exited with {}, see {:?}", status, log_path);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("The daemon did not open its API port within a minute, see {:?}", log_path);
+}
+
+fn stop_daemon(repo_path: &std::path::Path) {
+    let pid = match daemon_pid(repo_path) {
+        Some(pid) => pid,
+        None => {
+            println!("The daemon is not running.");
+            return;
+        }
+    };
+    let exit_status = std::process::Command::new("kill")
+        .arg(format!("{}", pid))
+        .status()
+        .expect("failed to execute process");
+    assert!(exit_status.success());
+    for _ in 0..300 {
+        if daemon_pid(repo_path).is_none() {
+            std::fs::remove_file(pid_path(repo_path)).unwrap();
+            println!("Stopped the daemon.");
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!(
+        "The daemon with pid {} did not stop within 30 seconds, see {:?}",
+        pid,
+        repo_path.join("daemon.log")
+    );
+}
+
+fn ipfs_name_publish(
+    repo_path: &std::path::Path,
+    key_name: &str,
+    hash: &str,
+    lifetime: &str,
+) -> String {
     let mut output = std::process::Command::new("sh")
         .arg("-c")
         .arg(format!(
-            "IPFS_PATH={}/ipfs ipfs name publish --resolve=false -Q --key={} {}",
+            "IPFS_PATH={}/ipfs ipfs name publish --resolve=false -Q --lifetime={} --key={} {}",
             repo_path.to_str().unwrap(),
+            lifetime,
             key_name,
             hash
         ))
@@ -525,3 +3030,281 @@ fn ipfs_key_rm(repo_path: &std::path::Path, key_name: &str) {
 
 
 
+
+fn ipfs_pin_ls(repo_path: &std::path::Path) -> Vec<String> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs pin ls --type=recursive -q",
+            repo_path.to_str().unwrap(),
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn ipfs_pin_rm(repo_path: &std::path::Path, cid: &str) {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs pin rm {}",
+            repo_path.to_str().unwrap(),
+            cid,
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success());
+}
+
+fn ipfs_repo_gc(repo_path: &std::path::Path) {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs repo gc -q",
+            repo_path.to_str().unwrap(),
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success());
+}
+
+/// Run `ipfs <args>` on the repo's node and return what it printed, minus
+/// the trailing newline.
+fn ipfs(repo_path: &std::path::Path, args: &str) -> String {
+    let mut output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs {}",
+            repo_path.to_str().unwrap(),
+            args
+        ))
+        .output()
+        .expect("failed to execute process");
+    use std::io::Write;
+    std::io::stderr().write_all(&output.stderr).unwrap();
+    assert!(output.status.success(), "ipfs {} failed", args);
+    if !output.stdout.is_empty() {
+        output.stdout.truncate(output.stdout.len() - 1);
+    }
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Like `ipfs`, but returns None instead of panicking if the command fails.
+fn try_ipfs(repo_path: &std::path::Path, args: &str) -> Option<String> {
+    let mut output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "IPFS_PATH={}/ipfs ipfs {}",
+            repo_path.to_str().unwrap(),
+            args
+        ))
+        .output()
+        .expect("failed to execute process");
+    if !output.status.success() {
+        return None;
+    }
+    if !output.stdout.is_empty() {
+        output.stdout.truncate(output.stdout.len() - 1);
+    }
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+fn ipfs_files_ls(repo_path: &std::path::Path, mfs_path: &str) -> Vec<String> {
+    ipfs(repo_path, &format!("files ls {}", mfs_path))
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const META: &str = "name = \"app\"\nversion = \"1.0.0\"\n";
+
+    /// A project lint_project has nothing to say about, but with `meta` as its
+    /// meta.toml.
+    fn project(meta: &str) -> TestDir {
+        let project = TestDir::new();
+        project.write("meta.toml", meta);
+        project.write("config.toml", "flags = []\narchs = []\nenums = []\n");
+        project.write("sps_build.sh", "set -e\nmkdir -p $SPS_DEST_DIR/usr/bin\n");
+        project
+    }
+
+    fn lint(project: &TestDir) -> Vec<String> {
+        lint_project(project.path())
+    }
+
+    #[test]
+    fn a_good_project_has_no_problems() {
+        let project = project(&format!("{}[dependencies]\nlib = \"^1.2\"\n", META));
+        assert!(lint(&project).is_empty(), "{:?}", lint(&project));
+    }
+
+    #[test]
+    fn missing_files_are_problems() {
+        let project = project(META);
+        std::fs::remove_file(project.path().join("config.toml")).unwrap();
+        std::fs::remove_file(project.path().join("sps_build.sh")).unwrap();
+        assert_eq!(
+            lint(&project),
+            ["config.toml is missing", "sps_build.sh is missing"]
+        );
+    }
+
+    #[test]
+    fn bad_names_and_versions_are_problems() {
+        let project = project("name = \".app\"\nversion = \"1.0\"\n");
+        let problems = lint(&project);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("not a valid package name"));
+        assert!(problems[1].contains("not a valid version"));
+    }
+
+    #[test]
+    fn bad_dependencies_are_problems() {
+        let project = project(&format!(
+            "{}[dependencies]\nlib = \"not a requirement\"\nother = 1\n",
+            META
+        ));
+        let problems = lint(&project);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("dependency lib"));
+        assert!(problems[1].contains("Dependency other"));
+    }
+
+    #[test]
+    fn enums_need_values() {
+        let project = project(META);
+        project.write(
+            "config.toml",
+            "flags = []\narchs = []\nenums = [\"tls\", \"gui\"]\ntls = []\n",
+        );
+        assert_eq!(
+            lint(&project),
+            [
+                "Enum tls in config.toml has no values",
+                "Enum gui needs gui = [] listing its values in config.toml",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_build_script_with_a_syntax_error_is_a_problem() {
+        let project = project(META);
+        project.write("sps_build.sh", "if true; then\n");
+        let problems = lint(&project);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("sps_build.sh has a syntax error"));
+    }
+
+    #[test]
+    fn a_negative_revision_is_a_problem() {
+        let project = project(&format!("{}revision = -1\n", META));
+        assert_eq!(
+            lint(&project),
+            ["revision in meta.toml must be a non-negative integer"]
+        );
+    }
+
+    #[test]
+    fn subpackages_need_unique_names_and_files() {
+        let project = project(&format!(
+            "{}[[subpackage]]\nname = \"app\"\nfiles = []\n[[subpackage]]\nname = \"app-doc\"\n",
+            META
+        ));
+        assert_eq!(
+            lint(&project),
+            [
+                "There is more than one package named app",
+                "Subpackage app-doc needs files = [] listing the patterns of its files",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_package_cannot_provide_itself() {
+        let project = project(&format!("{}provides = [\"app\", \"sh\"]\n", META));
+        assert_eq!(lint(&project), ["app cannot provide itself"]);
+    }
+
+    #[test]
+    fn hooks_are_checked_too() {
+        let project = project(META);
+        project.write("sps_pre_remove.sh", "if true; then\n");
+        let problems = lint(&project);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("sps_pre_remove.sh has a syntax error"));
+    }
+
+    #[test]
+    fn gc_keeps_the_pointers_rotate_key_left() {
+        let repo = TestDir::new();
+        repo.write("index/pkgs/app/1/1.0.0/index", "0 = \"bafyartifact\"\n");
+        repo.write("gc.tmp/pkgs/app/1/1.0.0/index", "0 = \"bafypublished\"\n");
+        write_published(
+            repo.path(),
+            &Published {
+                cid: "bafyindex".to_string(),
+                ..Published::default()
+            },
+        );
+        // What rotate-key records after pointing the old address at the new.
+        record_moved(repo.path(), "bafymoved");
+        let keep = gc_keep(
+            &repo.path().join("index"),
+            read_published(repo.path()).as_ref(),
+            &repo.path().join("gc.tmp"),
+        );
+        assert_eq!(
+            keep.into_iter().collect::<Vec<_>>(),
+            ["bafyartifact", "bafyindex", "bafymoved", "bafypublished"]
+        );
+        assert_eq!(published_moved(repo.path()), ["bafymoved"]);
+    }
+
+    #[test]
+    fn removed_packages_leave_providers_toml() {
+        let repo = TestDir::new();
+        let meta = |name: &str, version: &str, provides: &str| {
+            repo.write(
+                &format!("pkgs/{}/1/{}/meta.toml", name, version),
+                &format!(
+                    "name = \"{}\"\nversion = \"{}\"\nprovides = [{}]\n",
+                    name, version, provides
+                ),
+            )
+        };
+        meta("bash", "1.0.0", "\"sh\"");
+        meta("bash", "1.1.0", "\"sh\"");
+        meta("dash", "1.0.0", "\"sh\"");
+        repo.write("pkgs/sh/providers.toml", "providers = [\"bash\", \"dash\"]\n");
+        let providers = || crate::index::read_providers(repo.path(), "sh").providers;
+
+        // bash 1.1.0 still provides sh.
+        std::fs::remove_dir_all(repo.path().join("pkgs/bash/1/1.0.0")).unwrap();
+        remove_provider(repo.path(), "bash");
+        assert_eq!(providers(), ["bash", "dash"]);
+
+        std::fs::remove_dir_all(repo.path().join("pkgs/dash")).unwrap();
+        remove_provider(repo.path(), "dash");
+        assert_eq!(providers(), ["bash"]);
+
+        std::fs::remove_dir_all(repo.path().join("pkgs/bash")).unwrap();
+        remove_provider(repo.path(), "bash");
+        assert!(!repo.path().join("pkgs/sh").exists());
+    }
+}
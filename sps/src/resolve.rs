@@ -0,0 +1,865 @@
+use crate::index::{self, Candidate};
+use crate::state::{Alternatives, Channels, Installed, Pins};
+use semver::{Version, VersionReq};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+/// A package asked for on the command line.
+#[derive(Debug)]
+pub struct Request {
+    pub name: String,
+    pub req: VersionReq,
+    /// Only this packaging revision will do.
+    pub revision: Option<u64>,
+}
+
+/// Split `name`, `name@requirement` or `name@version_revision`, like
+/// hello@1.0_2, as given on the command line.
+pub fn parse_request(request: &str) -> Request {
+    let at = match request.find('@') {
+        Some(at) => at,
+        None => {
+            return Request {
+                name: request.to_string(),
+                req: VersionReq::any(),
+                revision: None,
+            }
+        }
+    };
+    let name = request[..at].to_string();
+    let req = &request[at + 1..];
+    // A version followed by _<number> asks for that revision of exactly
+    // that version.
+    if let Some((version, revision)) = req.rsplit_once('_') {
+        let mut version = version.to_string();
+        if version.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            while version.matches('.').count() < 2 {
+                version.push_str(".0");
+            }
+        }
+        let version = Version::parse(&version)
+            .unwrap_or_else(|e| panic!("Invalid version in {}: {}", request, e));
+        let revision = revision
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid revision in {}", request));
+        return Request {
+            name,
+            req: VersionReq::exact(&version),
+            revision: Some(revision),
+        };
+    }
+    Request {
+        name,
+        req: VersionReq::parse(req)
+            .unwrap_or_else(|e| panic!("Invalid version requirement in {}: {}", request, e)),
+        revision: None,
+    }
+}
+
+#[derive(Debug)]
+/// The versions replaced or removed are given with their revision.
+pub enum Action {
+    Install(Candidate),
+    Upgrade((Version, u64), Candidate),
+    Downgrade((Version, u64), Candidate),
+    Remove(String, (Version, u64)),
+}
+
+impl Action {
+    pub fn candidate(&self) -> Option<&Candidate> {
+        match self {
+            Action::Install(c) | Action::Upgrade(_, c) | Action::Downgrade(_, c) => Some(c),
+            Action::Remove(_, _) => None,
+        }
+    }
+}
+
+/// Everything a transaction will do, removals first and every package
+/// after its dependencies.
+#[derive(Debug)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+    /// Packages to be marked as installed on request.
+    pub explicit: BTreeSet<String>,
+}
+
+impl Plan {
+    pub fn print(&self) {
+        let show = |(v, r): &(Version, u64)| index::display_version(v, *r);
+        for action in self.actions.iter() {
+            let new = action
+                .candidate()
+                .map(|c| index::display_version(&c.version, c.revision));
+            if let Some(c) = action.candidate() {
+                if let Some(reason) = c.deprecated.as_ref() {
+                    println!(
+                        "    warning: {} {} is deprecated. {}",
+                        c.name,
+                        new.as_ref().unwrap(),
+                        reason
+                    );
+                }
+            }
+            let new = new.unwrap_or_default();
+            match action {
+                Action::Install(c) if self.explicit.contains(&c.name) => {
+                    println!("    install   {} {}", c.name, new)
+                }
+                Action::Install(c) => {
+                    println!("    install   {} {} (new dependency)", c.name, new)
+                }
+                Action::Upgrade(old, c) => {
+                    println!("    upgrade   {} {} -> {}", c.name, show(old), new)
+                }
+                Action::Downgrade(old, c) => {
+                    println!("    downgrade {} {} -> {}", c.name, show(old), new)
+                }
+                Action::Remove(name, old) => println!("    remove    {} {}", name, show(old)),
+            }
+        }
+    }
+}
+
+/// Warn about installed versions their repository has since yanked or
+/// deprecated. They stay installed, but should be moved away from.
+pub fn warn_retracted(root_path: &str, installed: &Installed) {
+    let repos = index::repos_by_priority(root_path);
+    for (name, p) in installed.packages.iter() {
+        let index_path = match repos.iter().find(|(hash, _)| *hash == p.repo) {
+            Some((_, index_path)) => index_path,
+            None => continue,
+        };
+        if !index::revisions_of(index_path, name).contains(&(p.version.clone(), p.revision)) {
+            continue;
+        }
+        let c = index::read_candidate(&p.repo, index_path, name, &p.version, p.revision);
+        let version = index::display_version(&p.version, p.revision);
+        if let Some(reason) = c.yanked {
+            eprintln!(
+                "warning: installed {} {} has been yanked. {}",
+                name, version, reason
+            );
+        } else if let Some(reason) = c.deprecated {
+            eprintln!(
+                "warning: installed {} {} is deprecated. {}",
+                name, version, reason
+            );
+        }
+    }
+}
+
+enum Selected {
+    /// The installed version, its dependencies and what it provides.
+    Kept(Version, Vec<(String, VersionReq)>, Vec<String>),
+    New(Candidate),
+}
+
+impl Selected {
+    fn version(&self) -> &Version {
+        match self {
+            Selected::Kept(v, _, _) => v,
+            Selected::New(c) => &c.version,
+        }
+    }
+    fn dependencies(&self) -> &[(String, VersionReq)] {
+        match self {
+            Selected::Kept(_, d, _) => d,
+            Selected::New(c) => &c.dependencies,
+        }
+    }
+    fn provides(&self) -> &[String] {
+        match self {
+            Selected::Kept(_, _, p) => p,
+            Selected::New(c) => &c.provides,
+        }
+    }
+}
+
+/// The selected package providing the virtual package `name`, if any.
+fn provider_of(selected: &BTreeMap<String, Selected>, name: &str) -> Option<String> {
+    selected
+        .iter()
+        .find(|(_, s)| s.provides().iter().any(|p| p == name))
+        .map(|(provider, _)| provider.clone())
+}
+
+/// Whether `req` allows every version, like the "*" virtual packages are
+/// depended on with.
+fn is_any(req: &VersionReq) -> bool {
+    *req == VersionReq::any() || *req == VersionReq::parse("*").unwrap()
+}
+
+/// Whether a comparator of `req` names a pre-release, like >=1.0.0-rc1.
+fn names_prerelease(req: &VersionReq) -> bool {
+    semver_parser::range::parse(&req.to_string())
+        .unwrap()
+        .predicates
+        .iter()
+        .any(|p| !p.pre.is_empty())
+}
+
+/// Whether `version` satisfies every comparator of `req`. Unlike
+/// `req.matches`, a pre-release also matches a requirement that names none,
+/// so 1.3.0-rc1 matches ^1.0, for when pre-releases are allowed.
+pub fn satisfies(req: &VersionReq, version: &Version) -> bool {
+    if !version.is_prerelease() || req.matches(version) {
+        return req.matches(version);
+    }
+    // semver only compares a pre-release against a requirement naming its
+    // own major, minor and patch version, so add one that all of them meet.
+    let anchored = format!(
+        "{}, >={}.{}.{}-0",
+        req, version.major, version.minor, version.patch
+    );
+    VersionReq::parse(&anchored).unwrap().matches(version)
+}
+
+/// Work out what has to change so every package in `targets` is at the
+/// newest version and revision matching its request and `removals` are gone, with
+/// all dependencies satisfied and every pin and hold respected. Unless
+/// `allow_major` is set, installed targets stay within their current major
+/// version.
+///
+/// Pre-releases are left out unless `pre` is set, the requirement names
+/// one, the installed version is one, or the channel followed for the
+/// repository takes it. Those allowed match ranged requirements too, of
+/// the request as of dependencies.
+///
+/// A virtual package is satisfied by the package providing it that is
+/// installed, else by the one preferred with sps alternatives, else by the
+/// first provider in the highest priority repository.
+#[allow(clippy::too_many_arguments)]
+pub fn resolve(
+    root_path: &str,
+    installed: &Installed,
+    pins: &Pins,
+    alternatives: &Alternatives,
+    targets: &[Request],
+    removals: &[String],
+    allow_major: bool,
+    pre: bool,
+) -> Plan {
+    let repos = index::repos_by_priority(root_path);
+    let channels = Channels::load(&PathBuf::from(format!("{}/usr/sps/repos", root_path)));
+    // The newest version of `name` matching `req` that the pins and holds
+    // allow, or a panic explaining why there is none.
+    let pick = |name: &str,
+                req: &VersionReq,
+                revision: Option<u64>,
+                major: Option<u64>,
+                wanted_by: &str| {
+        let in_major = |v: &Version| major.is_none_or(|m| v.major == m);
+        let current = installed
+            .packages
+            .get(name)
+            .map(|p| (&p.version, p.revision));
+        let any_pre =
+            pre || names_prerelease(req) || current.is_some_and(|(v, _)| v.is_prerelease());
+        let wanted = |repo: &str, v: &Version, r: u64| {
+            satisfies(req, v)
+                && in_major(v)
+                && revision.is_none_or(|wanted| r == wanted)
+                && (any_pre || channels.get(repo).accepts(v))
+        };
+        if let Some(c) = index::newest(&repos, name, current, |repo, v, r| {
+            wanted(repo, v, r) && pins.allows(name, v, r, installed)
+        }) {
+            return c;
+        }
+        if index::newest(&repos, name, current, wanted).is_some() {
+            if pins.holds.contains(name) && installed.packages.contains_key(name) {
+                panic!(
+                    "{} {} {}, but {} is held at {}",
+                    wanted_by, name, req, name, installed.packages[name].version
+                );
+            }
+            panic!(
+                "{} {} {}, which conflicts with the pin {} {}",
+                wanted_by, name, req, name, pins.pins[name]
+            );
+        }
+        if !any_pre
+            && index::newest(&repos, name, current, |_, v, r| {
+                satisfies(req, v) && in_major(v) && revision.is_none_or(|wanted| r == wanted)
+            })
+            .is_some()
+        {
+            panic!(
+                "{} {} {}, which only pre-releases match. Pass --pre or follow a channel that has them with sps channel.",
+                wanted_by, name, req
+            );
+        }
+        match (major, revision) {
+            (_, Some(revision)) => panic!(
+                "{} {} {} revision {}, which no repository has",
+                wanted_by, name, req, revision
+            ),
+            (Some(major), None) => panic!(
+                "No repository has {} {} within major version {}. Use sps upgrade --major to move to another major version.",
+                name, req, major
+            ),
+            (None, None) => panic!("{} {} {}, which no repository has", wanted_by, name, req),
+        }
+    };
+
+    // The newest version of a package providing the virtual package
+    // `name`, or a panic if there is none.
+    let choose_provider = |name: &str, wanted_by: &str| {
+        let mut providers: Vec<String> = alternatives.preferred.get(name).cloned().into_iter().collect();
+        for (_, index_path) in repos.iter() {
+            for provider in index::read_providers(index_path, name).providers {
+                if !providers.contains(&provider) {
+                    providers.push(provider);
+                }
+            }
+        }
+        for provider in providers.iter().filter(|p| !removals.contains(p)) {
+            let current = installed
+                .packages
+                .get(provider)
+                .map(|p| (&p.version, p.revision));
+            let newest = index::newest(&repos, provider, current, |repo, v, r| {
+                (pre || channels.get(repo).accepts(v)) && pins.allows(provider, v, r, installed)
+            });
+            if let Some(c) = newest.filter(|c| c.provides.iter().any(|p| p == name)) {
+                return c;
+            }
+        }
+        panic!("{} {}, which no available package provides", wanted_by, name);
+    };
+
+    for name in removals.iter() {
+        assert!(
+            installed.packages.contains_key(name),
+            "{} is not installed",
+            name
+        );
+        assert!(
+            !pins.holds.contains(name),
+            "{} is held, run sps unhold {} before removing it",
+            name,
+            name
+        );
+    }
+    let mut selected: BTreeMap<String, Selected> = installed
+        .packages
+        .iter()
+        .filter(|(name, _)| !removals.contains(name))
+        .map(|(name, p)| {
+            (
+                name.clone(),
+                Selected::Kept(
+                    p.version.clone(),
+                    p.dependencies
+                        .iter()
+                        .map(|(d, r)| (d.clone(), r.clone()))
+                        .collect(),
+                    p.provides.clone(),
+                ),
+            )
+        })
+        .collect();
+    let mut explicit: BTreeSet<String> = installed
+        .packages
+        .iter()
+        .filter(|(name, p)| p.explicit && !removals.contains(name))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    // The provider picked for each virtual package depended on.
+    let mut virtuals: BTreeMap<String, String> = BTreeMap::new();
+    for t in targets.iter() {
+        let mut name = t.name.clone();
+        if !installed.packages.contains_key(&name) && index::is_virtual(&repos, &name) {
+            assert!(
+                is_any(&t.req) && t.revision.is_none(),
+                "{} is a virtual package, it has no versions",
+                name
+            );
+            let provider = provider_of(&selected, &name)
+                .unwrap_or_else(|| choose_provider(&name, "Requested").name);
+            println!("Using {} for {}.", provider, name);
+            virtuals.insert(name, provider.clone());
+            name = provider;
+        }
+        let major = match installed.packages.get(&name) {
+            Some(p) if !allow_major && t.revision.is_none() => Some(p.version.major),
+            _ => None,
+        };
+        let candidate = pick(&name, &t.req, t.revision, major, "Requested");
+        explicit.insert(name.clone());
+        selected.insert(name, Selected::New(candidate));
+    }
+
+    let mut work: Vec<String> = selected.keys().cloned().collect();
+    while let Some(name) = work.pop() {
+        let dependencies = selected[&name].dependencies().to_vec();
+        for (dep, req) in dependencies {
+            assert!(
+                !removals.contains(&dep),
+                "Cannot remove {}, {} depends on it",
+                dep,
+                name
+            );
+            if !selected.contains_key(&dep) && index::is_virtual(&repos, &dep) {
+                assert!(
+                    is_any(&req),
+                    "{} needs {} {}, but {} is a virtual package, it has no versions",
+                    name,
+                    dep,
+                    req,
+                    dep
+                );
+                match provider_of(&selected, &dep) {
+                    Some(provider) => {
+                        virtuals.insert(dep, provider);
+                    }
+                    None => {
+                        let candidate = choose_provider(&dep, &format!("{} needs", name));
+                        virtuals.insert(dep, candidate.name.clone());
+                        work.push(candidate.name.clone());
+                        selected.insert(candidate.name.clone(), Selected::New(candidate));
+                    }
+                }
+                continue;
+            }
+            if let Some(s) = selected.get(&dep) {
+                if satisfies(&req, s.version()) {
+                    continue;
+                }
+                assert!(
+                    !targets
+                        .iter()
+                        .any(|t| t.name == dep && (t.req != VersionReq::any() || t.revision.is_some())),
+                    "{} {} was requested but {} needs {} {}",
+                    dep,
+                    s.version(),
+                    name,
+                    dep,
+                    req
+                );
+            }
+            let candidate = pick(&dep, &req, None, None, &format!("{} needs", name));
+            selected.insert(dep.clone(), Selected::New(candidate));
+            work.push(dep);
+        }
+    }
+    for (name, s) in selected.iter() {
+        for (dep, req) in s.dependencies() {
+            if virtuals.contains_key(dep) {
+                continue;
+            }
+            assert!(
+                selected.get(dep).is_some_and(|d| satisfies(req, d.version())),
+                "Could not find a consistent set of versions: {} needs {} {}",
+                name,
+                dep,
+                req
+            );
+        }
+    }
+
+    // Walk down from the explicitly installed packages. Whatever is not
+    // reached is an automatically installed package nothing needs any more.
+    let mut order = Vec::new();
+    let mut visited = BTreeSet::new();
+    fn visit(
+        name: &str,
+        selected: &BTreeMap<String, Selected>,
+        virtuals: &BTreeMap<String, String>,
+        visited: &mut BTreeSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        for (dep, _) in selected[name].dependencies() {
+            let dep = virtuals.get(dep).unwrap_or(dep);
+            visit(dep, selected, virtuals, visited, order);
+        }
+        order.push(name.to_string());
+    }
+    for name in explicit.iter() {
+        visit(name, &selected, &virtuals, &mut visited, &mut order);
+    }
+
+    let mut actions = Vec::new();
+    for (name, p) in installed.packages.iter() {
+        if !visited.contains(name) {
+            actions.push(Action::Remove(name.clone(), (p.version.clone(), p.revision)));
+        }
+    }
+    for name in order {
+        if let Some(Selected::New(c)) = selected.remove(&name) {
+            match installed.packages.get(&name) {
+                None => actions.push(Action::Install(c)),
+                Some(p) if (&c.version, c.revision) > (&p.version, p.revision) => {
+                    actions.push(Action::Upgrade((p.version.clone(), p.revision), c))
+                }
+                Some(p) if (&c.version, c.revision) < (&p.version, p.revision) => {
+                    actions.push(Action::Downgrade((p.version.clone(), p.revision), c))
+                }
+                Some(_) => (),
+            }
+        }
+    }
+    // Refuse a package not built for this machine before anything is fetched.
+    for c in actions.iter().filter_map(|a| a.candidate()) {
+        assert!(
+            crate::transaction::pick_variant(c, std::env::consts::ARCH).is_some(),
+            "There is no variant of {} {} for {}",
+            c.name,
+            index::display_version(&c.version, c.revision),
+            std::env::consts::ARCH
+        );
+    }
+    Plan { actions, explicit }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// A root with one repository added, called repo.
+    fn root() -> TestDir {
+        let root = TestDir::new();
+        root.write("usr/sps/repos/priority", "repo = 10\n");
+        root
+    }
+
+    /// Publish `version` of `name` in the repository, with `extra` lines of
+    /// meta.toml, built for every arch.
+    fn publish(root: &TestDir, name: &str, version: &str, extra: &str) {
+        let major = Version::parse(version).unwrap().major;
+        root.write(
+            &format!(
+                "usr/sps/repos/repo/pkgs/{}/{}/{}/meta.toml",
+                name, major, version
+            ),
+            &format!("name = \"{}\"\nversion = \"{}\"\n{}", name, version, extra),
+        );
+        root.write(
+            &format!(
+                "usr/sps/repos/repo/pkgs/{}/{}/{}/config.toml",
+                name, major, version
+            ),
+            "flags = []\narchs = []\nenums = []\n",
+        );
+    }
+
+    /// Packages installed on request, by name and version.
+    fn installed(packages: &[(&str, &str)]) -> Installed {
+        let mut installed = String::new();
+        for (name, version) in packages.iter() {
+            installed.push_str(&format!(
+            "[packages.{}]\nversion = \"{}\"\nrepo = \"repo\"\nvariant = 0\ncid = \"\"\nexplicit = true\nfiles = []\n",
+            name, version
+        ));
+        }
+        toml::from_str(&installed).unwrap()
+    }
+
+    fn hello(root: &TestDir) {
+        for version in ["1.0.0", "1.1.0", "2.0.0"].iter() {
+            publish(root, "hello", version, "");
+        }
+    }
+
+    /// Resolve `requests` like sps install does, as "name version" for each
+    /// package installed, upgraded or downgraded.
+    fn plan(root: &TestDir, installed: &Installed, requests: &[&str]) -> Vec<String> {
+        plan_with(root, installed, requests, false)
+    }
+
+    /// Like plan, with --pre.
+    fn plan_pre(root: &TestDir, requests: &[&str]) -> Vec<String> {
+        plan_with(root, &Installed::default(), requests, true)
+    }
+
+    fn plan_with(
+        root: &TestDir,
+        installed: &Installed,
+        requests: &[&str],
+        pre: bool,
+    ) -> Vec<String> {
+        let targets: Vec<_> = requests.iter().map(|r| parse_request(r)).collect();
+        let pins = Pins::load(root.str());
+        let alternatives = Alternatives::load(root.str());
+        resolve(
+            root.str(),
+            installed,
+            &pins,
+            &alternatives,
+            &targets,
+            &[],
+            false,
+            pre,
+        )
+        .actions
+        .iter()
+        .filter_map(|a| a.candidate())
+        .map(|c| format!("{} {}", c.name, c.version))
+        .collect()
+    }
+
+    #[test]
+    fn picks_the_newest_matching_version() {
+        let root = root();
+        hello(&root);
+        let none = Installed::default();
+        assert_eq!(plan(&root, &none, &["hello"]), ["hello 2.0.0"]);
+        assert_eq!(plan(&root, &none, &["hello@^1.0"]), ["hello 1.1.0"]);
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let root = root();
+        hello(&root);
+        publish(
+            &root,
+            "greeter",
+            "1.0.0",
+            "[dependencies]\nhello = \"^1.0\"\n",
+        );
+        assert_eq!(
+            plan(&root, &Installed::default(), &["greeter"]),
+            ["hello 1.1.0", "greeter 1.0.0"]
+        );
+    }
+
+    #[test]
+    fn upgrades_stay_within_the_major_version() {
+        let root = root();
+        hello(&root);
+        assert_eq!(
+            plan(&root, &installed(&[("hello", "1.0.0")]), &["hello"]),
+            ["hello 1.1.0"]
+        );
+    }
+
+    #[test]
+    fn pins_limit_the_versions() {
+        let root = root();
+        hello(&root);
+        root.write("usr/sps/pins.toml", "[pins]\nhello = \"^1.0\"\n");
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello"]),
+            ["hello 1.1.0"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "which conflicts with the pin hello ^1.0")]
+    fn a_request_outside_the_pin_is_refused() {
+        let root = root();
+        hello(&root);
+        root.write("usr/sps/pins.toml", "[pins]\nhello = \"^1.0\"\n");
+        plan(&root, &Installed::default(), &["hello@^2.0"]);
+    }
+
+    #[test]
+    fn holds_keep_the_installed_version() {
+        let root = root();
+        hello(&root);
+        root.write("usr/sps/pins.toml", "holds = [\"hello\"]\n");
+        assert!(plan(&root, &installed(&[("hello", "1.0.0")]), &["hello"]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "but hello is held at 1.0.0")]
+    fn a_request_moving_a_held_package_is_refused() {
+        let root = root();
+        hello(&root);
+        root.write("usr/sps/pins.toml", "holds = [\"hello\"]\n");
+        plan(&root, &installed(&[("hello", "1.0.0")]), &["hello@1.1.0"]);
+    }
+
+    fn pre_releases(root: &TestDir) {
+        for version in ["1.0.0", "1.1.0", "1.2.0-rc1", "1.3.0-nightly.1"].iter() {
+            publish(root, "hello", version, "");
+        }
+    }
+
+    #[test]
+    fn pre_releases_are_left_out() {
+        let root = root();
+        pre_releases(&root);
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello"]),
+            ["hello 1.1.0"]
+        );
+        assert_eq!(plan_pre(&root, &["hello"]), ["hello 1.3.0-nightly.1"]);
+    }
+
+    #[test]
+    fn channels_take_their_pre_releases() {
+        let root = root();
+        pre_releases(&root);
+        root.write("usr/sps/repos/channels.toml", "[repos]\nrepo = \"beta\"\n");
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello"]),
+            ["hello 1.2.0-rc1"]
+        );
+        root.write(
+            "usr/sps/repos/channels.toml",
+            "[repos]\nrepo = \"nightly\"\n",
+        );
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello"]),
+            ["hello 1.3.0-nightly.1"]
+        );
+    }
+
+    #[test]
+    fn a_requirement_naming_a_pre_release_matches_it() {
+        let root = root();
+        pre_releases(&root);
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello@=1.2.0-rc1"]),
+            ["hello 1.2.0-rc1"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "which only pre-releases match")]
+    fn only_pre_releases_matching_is_explained() {
+        let root = root();
+        pre_releases(&root);
+        plan(&root, &Installed::default(), &["hello@~1.2"]);
+    }
+
+    #[test]
+    fn allowed_pre_releases_match_ranges() {
+        let root = root();
+        pre_releases(&root);
+        publish(&root, "hello", "2.0.0", "");
+        assert_eq!(plan_pre(&root, &["hello@^1.0"]), ["hello 1.3.0-nightly.1"]);
+        assert_eq!(
+            plan_pre(&root, &["hello@<1.3.0"]),
+            ["hello 1.3.0-nightly.1"]
+        );
+        assert_eq!(plan_pre(&root, &["hello@>=1.3.0"]), ["hello 2.0.0"]);
+        root.write("usr/sps/repos/channels.toml", "[repos]\nrepo = \"beta\"\n");
+        assert_eq!(
+            plan(&root, &Installed::default(), &["hello@^1.0"]),
+            ["hello 1.2.0-rc1"]
+        );
+    }
+
+    #[test]
+    fn allowed_pre_releases_match_dependencies() {
+        let root = root();
+        pre_releases(&root);
+        publish(
+            &root,
+            "greeter",
+            "1.0.0",
+            "[dependencies]\nhello = \"^1.0\"\n",
+        );
+        assert_eq!(
+            plan(&root, &Installed::default(), &["greeter"]),
+            ["hello 1.1.0", "greeter 1.0.0"]
+        );
+        assert_eq!(
+            plan_pre(&root, &["greeter"]),
+            ["hello 1.3.0-nightly.1", "greeter 1.0.0"]
+        );
+    }
+
+    fn shells(root: &TestDir) {
+        publish(root, "bash", "1.0.0", "provides = [\"sh\"]\n");
+        publish(root, "dash", "1.0.0", "provides = [\"sh\"]\n");
+        root.write(
+            "usr/sps/repos/repo/pkgs/sh/providers.toml",
+            "providers = [\"bash\", \"dash\"]\n",
+        );
+        publish(root, "script", "1.0.0", "[dependencies]\nsh = \"*\"\n");
+    }
+
+    #[test]
+    fn the_first_provider_is_chosen() {
+        let root = root();
+        shells(&root);
+        assert_eq!(
+            plan(&root, &Installed::default(), &["script"]),
+            ["bash 1.0.0", "script 1.0.0"]
+        );
+    }
+
+    #[test]
+    fn the_preferred_provider_is_chosen() {
+        let root = root();
+        shells(&root);
+        root.write("usr/sps/alternatives.toml", "[preferred]\nsh = \"dash\"\n");
+        assert_eq!(
+            plan(&root, &Installed::default(), &["script"]),
+            ["dash 1.0.0", "script 1.0.0"]
+        );
+    }
+
+    #[test]
+    fn an_installed_provider_is_kept() {
+        let root = root();
+        shells(&root);
+        let mut installed = installed(&[("dash", "1.0.0")]);
+        installed.packages.get_mut("dash").unwrap().provides = vec!["sh".to_string()];
+        assert_eq!(plan(&root, &installed, &["script"]), ["script 1.0.0"]);
+    }
+
+    #[test]
+    fn the_variant_for_this_arch_is_picked() {
+        let root = root();
+        publish(&root, "hello", "1.0.0", "");
+        let config = |archs: &str| {
+            root.write(
+                "usr/sps/repos/repo/pkgs/hello/1/1.0.0/config.toml",
+                &format!("flags = []\narchs = [{}]\nenums = []\n", archs),
+            )
+        };
+        let candidate = || {
+            let index_path = root.path().join("usr/sps/repos/repo");
+            index::read_candidate(
+                "repo",
+                &index_path,
+                "hello",
+                &Version::parse("1.0.0").unwrap(),
+                0,
+            )
+        };
+        use crate::transaction::pick_variant;
+        config("");
+        assert_eq!(pick_variant(&candidate(), "x86_64"), Some(0));
+        config("\"aarch64\", \"x86_64\"");
+        assert_eq!(pick_variant(&candidate(), "x86_64"), Some(1));
+        config("\"aarch64\"");
+        assert_eq!(pick_variant(&candidate(), "x86_64"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no variant of hello 1.0.0 for")]
+    fn a_package_not_built_for_this_arch_is_refused() {
+        let root = root();
+        publish(&root, "hello", "1.0.0", "");
+        root.write(
+            "usr/sps/repos/repo/pkgs/hello/1/1.0.0/config.toml",
+            "flags = []\narchs = [\"no-such-arch\"]\nenums = []\n",
+        );
+        plan(&root, &Installed::default(), &["hello"]);
+    }
+
+    #[test]
+    fn an_underscore_asks_for_a_revision() {
+        let v = |v: &str| Version::parse(v).unwrap();
+        let request = parse_request("hello@1.0_2");
+        assert_eq!(request.revision, Some(2));
+        assert!(request.req.matches(&v("1.0.0")) && !request.req.matches(&v("1.0.1")));
+        let request = parse_request("hello@1.0.0-rc1_2");
+        assert_eq!(request.revision, Some(2));
+        assert!(request.req.matches(&v("1.0.0-rc1")));
+        // A dash starts a pre-release, even a numeric one.
+        let request = parse_request("hello@=1.0.0-1");
+        assert_eq!(request.revision, None);
+        assert!(request.req.matches(&v("1.0.0-1")) && !request.req.matches(&v("1.0.0")));
+    }
+}
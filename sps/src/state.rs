@@ -0,0 +1,381 @@
+use semver::{Version, VersionReq};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    pub version: Version,
+    #[serde(default)]
+    pub revision: u64,
+    /// Hash of the repository it was installed from.
+    pub repo: String,
+    pub variant: usize,
+    pub cid: String,
+    /// Installed on request rather than pulled in as a dependency.
+    pub explicit: bool,
+    /// Installed files, relative to `SPS_ROOT_DIR`.
+    pub files: Vec<String>,
+    /// Virtual packages it provides.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+    /// The sha256 of each config file as the package shipped it, to tell
+    /// whether it was edited since.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub config: BTreeMap<String, String>,
+}
+
+/// The packages installed under `SPS_ROOT_DIR`, kept in `usr/sps/installed.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Installed {
+    #[serde(default)]
+    pub packages: BTreeMap<String, InstalledPackage>,
+}
+
+fn installed_path(root_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/installed.toml", root_path))
+}
+
+impl Installed {
+    pub fn load(root_path: &str) -> Installed {
+        match std::fs::read_to_string(installed_path(root_path)) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/installed.toml is corrupt"),
+            Err(_) => Installed::default(),
+        }
+    }
+
+    /// Write the database next to the old one and rename it over, so a
+    /// crash never leaves a half written database behind.
+    pub fn save(&self, root_path: &str) {
+        let path = installed_path(root_path);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+}
+
+/// What is left to do of a transaction that is changing files, kept in
+/// `usr/sps/journal.toml` until it is done, so one cut short can be
+/// finished.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    /// Where the packages being installed were built.
+    pub stage_path: PathBuf,
+    /// Every file the transaction installs. None of them is removed with
+    /// the package it used to belong to.
+    pub incoming: Vec<String>,
+    /// In order, removing each one once it is done.
+    pub steps: Vec<Step>,
+}
+
+/// Removing or installing one package.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Step {
+    pub name: String,
+    /// The versions it goes from and to, empty for none.
+    pub old: String,
+    pub new: String,
+    /// Where its files are staged, for a package being installed.
+    pub dest_path: Option<PathBuf>,
+    /// Which of its files are config files.
+    #[serde(default)]
+    pub config: Vec<String>,
+    /// Where its hooks are.
+    pub hooks_path: Option<PathBuf>,
+    /// Whether its pre-install or pre-remove hook has run, so finishing the
+    /// transaction does not run it again.
+    #[serde(default)]
+    pub pre_hook_done: bool,
+    /// The package as it is recorded once installed, None when it is
+    /// removed.
+    pub package: Option<InstalledPackage>,
+}
+
+fn journal_path(root_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/journal.toml", root_path))
+}
+
+impl Journal {
+    pub fn load(root_path: &str) -> Option<Journal> {
+        std::fs::read_to_string(journal_path(root_path))
+            .ok()
+            .map(|s| toml::from_str(&s).expect("usr/sps/journal.toml is corrupt"))
+    }
+
+    pub fn save(&self, root_path: &str) {
+        let path = journal_path(root_path);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+
+    pub fn clear(root_path: &str) {
+        std::fs::remove_file(journal_path(root_path)).unwrap();
+    }
+}
+
+/// Version pins and holds, kept in `usr/sps/pins.toml`. A pinned package
+/// only ever gets versions matching its requirement, a held one stays at
+/// the version that is installed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Pins {
+    #[serde(default)]
+    pub holds: BTreeSet<String>,
+    #[serde(default)]
+    pub pins: BTreeMap<String, VersionReq>,
+}
+
+fn pins_path(root_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/pins.toml", root_path))
+}
+
+impl Pins {
+    pub fn load(root_path: &str) -> Pins {
+        match std::fs::read_to_string(pins_path(root_path)) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/pins.toml is corrupt"),
+            Err(_) => Pins::default(),
+        }
+    }
+
+    pub fn save(&self, root_path: &str) {
+        let path = pins_path(root_path);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+
+    /// Whether `version` of `name` may be installed, as `revision`.
+    pub fn allows(&self, name: &str, version: &Version, revision: u64, installed: &Installed) -> bool {
+        if let Some(req) = self.pins.get(name) {
+            if !crate::resolve::satisfies(req, version) {
+                return false;
+            }
+        }
+        if self.holds.contains(name) {
+            if let Some(p) = installed.packages.get(name) {
+                return p.version == *version && p.revision == revision;
+            }
+        }
+        true
+    }
+}
+
+/// The provider chosen for each virtual package with sps alternatives,
+/// kept in `usr/sps/alternatives.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Alternatives {
+    #[serde(default)]
+    pub preferred: BTreeMap<String, String>,
+}
+
+fn alternatives_path(root_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/alternatives.toml", root_path))
+}
+
+impl Alternatives {
+    pub fn load(root_path: &str) -> Alternatives {
+        match std::fs::read_to_string(alternatives_path(root_path)) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/alternatives.toml is corrupt"),
+            Err(_) => Alternatives::default(),
+        }
+    }
+
+    pub fn save(&self, root_path: &str) {
+        let path = alternatives_path(root_path);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeenIndex {
+    pub generation: u64,
+    /// The publish time from the index manifest. An index of the same
+    /// generation published at another time is refused.
+    pub published: u64,
+    /// When it was fetched.
+    pub fetched: u64,
+    /// Whether the index was signed. Once one was, unsigned ones are refused.
+    #[serde(default)]
+    pub signed: bool,
+    /// The serial of the list of maintainers in the index. An older list
+    /// may still name a revoked maintainer, so it is refused.
+    #[serde(default)]
+    pub maintainers_serial: u64,
+    /// The CID of the packages, which an index of the same generation must
+    /// have too. Absent if the index had none, or was seen by an older sps.
+    #[serde(default)]
+    pub pkgs: Option<String>,
+}
+
+/// The newest index generation fetched from each repository, kept in
+/// `usr/sps/repos/seen.toml` so an older index can be refused.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SeenIndexes {
+    #[serde(default)]
+    pub repos: BTreeMap<String, SeenIndex>,
+}
+
+impl SeenIndexes {
+    pub fn load(repos_path: &Path) -> SeenIndexes {
+        let mut path = repos_path.to_path_buf();
+        path.push("seen.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/repos/seen.toml is corrupt"),
+            Err(_) => SeenIndexes::default(),
+        }
+    }
+
+    pub fn save(&self, repos_path: &Path) {
+        let mut path = repos_path.to_path_buf();
+        path.push("seen.toml");
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+}
+
+/// Which pre-releases a repository may provide. Each channel takes the
+/// versions of the ones before it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Releases only.
+    #[default]
+    Stable,
+    /// Also alpha, beta and rc pre-releases.
+    Beta,
+    /// Also every other pre-release, like 1.0.0-nightly.20240101.
+    Nightly,
+}
+
+impl std::str::FromStr for Channel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Channel, String> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "beta" => Ok(Channel::Beta),
+            "nightly" => Ok(Channel::Nightly),
+            _ => Err(format!("{} is not a channel, use stable, beta or nightly", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Channel::Stable => write!(f, "stable"),
+            Channel::Beta => write!(f, "beta"),
+            Channel::Nightly => write!(f, "nightly"),
+        }
+    }
+}
+
+impl Channel {
+    /// The first channel `version` is published on, going by the first
+    /// identifier of its pre-release.
+    pub fn of(version: &Version) -> Channel {
+        match version.pre.first() {
+            None => Channel::Stable,
+            Some(semver::Identifier::AlphaNumeric(tag))
+                if ["alpha", "beta", "rc"]
+                    .iter()
+                    .any(|t| tag.to_lowercase().starts_with(t)) =>
+            {
+                Channel::Beta
+            }
+            Some(_) => Channel::Nightly,
+        }
+    }
+
+    pub fn accepts(&self, version: &Version) -> bool {
+        Channel::of(version) <= *self
+    }
+}
+
+/// The channel followed for each repository, kept in
+/// `usr/sps/repos/channels.toml`. Repositories not listed are stable.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Channels {
+    #[serde(default)]
+    pub repos: BTreeMap<String, Channel>,
+}
+
+impl Channels {
+    pub fn load(repos_path: &Path) -> Channels {
+        let mut path = repos_path.to_path_buf();
+        path.push("channels.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/repos/channels.toml is corrupt"),
+            Err(_) => Channels::default(),
+        }
+    }
+
+    pub fn save(&self, repos_path: &Path) {
+        let mut path = repos_path.to_path_buf();
+        path.push("channels.toml");
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+
+    pub fn get(&self, repo: &str) -> Channel {
+        self.repos.get(repo).copied().unwrap_or_default()
+    }
+}
+
+/// The repository each mirror added with add-repo --mirror-of copies, kept
+/// in `usr/sps/repos/mirrors.toml`. The upstream key may sign the index
+/// the mirror serves.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Mirrors {
+    #[serde(default)]
+    pub repos: BTreeMap<String, String>,
+}
+
+impl Mirrors {
+    pub fn load(repos_path: &Path) -> Mirrors {
+        let mut path = repos_path.to_path_buf();
+        path.push("mirrors.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).expect("usr/sps/repos/mirrors.toml is corrupt"),
+            Err(_) => Mirrors::default(),
+        }
+    }
+
+    pub fn save(&self, repos_path: &Path) {
+        let mut path = repos_path.to_path_buf();
+        path.push("mirrors.toml");
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("toml.new");
+        std::fs::write(&tmp_path, toml::to_string(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+    }
+}
+
+/// Take the lock guarding the installed packages. It is held until the
+/// returned file is dropped.
+pub fn lock(root_path: &str) -> std::fs::File {
+    use fs2::FileExt;
+    let sps_path = PathBuf::from(format!("{}/usr/sps", root_path));
+    std::fs::create_dir_all(&sps_path).unwrap();
+    let mut lock_path = sps_path;
+    lock_path.push("lock");
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .unwrap();
+    lock_file.lock_exclusive().unwrap();
+    lock_file
+}
@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system's temp dir for a test, removed with
+/// everything in it when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        let mut path = std::env::temp_dir();
+        path.push(format!("sps-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Write `contents` to `file`, relative to the directory, creating the
+    /// directories it is in.
+    pub fn write(&self, file: &str, contents: &str) {
+        let path = self.path.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
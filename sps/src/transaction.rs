@@ -0,0 +1,575 @@
+use crate::index::Candidate;
+use crate::resolve::{Action, Plan};
+use crate::state::{Installed, InstalledPackage, Journal, Step};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A package fetched and built, waiting to be copied into place.
+struct Staged {
+    variant: usize,
+    cid: String,
+    dest_path: PathBuf,
+    files: Vec<String>,
+    /// Those of `files` that are config files.
+    config: Vec<String>,
+    /// The directory the variant was built in, holding its hooks.
+    /// Subpackages have none.
+    hooks_path: Option<PathBuf>,
+}
+
+/// The directory packages are built in for one transaction, removed when
+/// dropped unless a journal still needs it.
+struct StageDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for StageDir {
+    fn drop(&mut self) {
+        if !self.keep && self.path.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Carry out `plan` and record the result in `installed`. Every package is
+/// fetched and built before anything under `SPS_ROOT_DIR` is touched, so a
+/// failing download or build leaves the system as it was. The packages are
+/// then installed and removed in dependency order, each right after its
+/// pre-install or pre-remove hook. If a hook fails, the packages before it
+/// stay done and it and the ones after it are left as they were. The post
+/// hooks run once the result is recorded.
+///
+/// The steps left are kept in a journal while files change, and each
+/// package is recorded as soon as its files are in place, so a transaction
+/// cut short is finished by `finish_interrupted`.
+pub fn execute(root_path: &str, plan: &Plan, installed: &mut Installed) {
+    use uuid::Uuid;
+    let mut stage_dir = StageDir {
+        path: PathBuf::from(format!(
+            "{}/usr/sps/tmp/{}",
+            root_path,
+            Uuid::new_v4()
+                .to_simple()
+                .encode_lower(&mut Uuid::encode_buffer())
+        )),
+        keep: false,
+    };
+    std::fs::create_dir_all(&stage_dir.path).unwrap();
+
+    let mut staged = BTreeMap::new();
+    for c in plan.actions.iter().filter_map(|a| a.candidate()) {
+        println!(
+            "Building {} {}...",
+            c.name,
+            crate::index::display_version(&c.version, c.revision)
+        );
+        staged.insert(c.name.clone(), stage(c, &stage_dir.path));
+    }
+
+    // Files can move between packages, like into a newly split off one, so
+    // none that is about to be installed again is removed.
+    let incoming: Vec<String> = staged
+        .values()
+        .flat_map(|s: &Staged| s.files.iter().cloned())
+        .collect();
+    let steps = plan
+        .actions
+        .iter()
+        .map(|action| {
+            let (name, old, new) = action_versions(action);
+            match action {
+                Action::Remove(..) => Step {
+                    name: name.to_string(),
+                    old,
+                    new,
+                    dest_path: None,
+                    config: Vec::new(),
+                    hooks_path: Some(installed_hooks_path(root_path, name)),
+                    pre_hook_done: false,
+                    package: None,
+                },
+                Action::Install(c) | Action::Upgrade(_, c) | Action::Downgrade(_, c) => {
+                    let s = staged.get(&c.name).unwrap();
+                    Step {
+                        name: name.to_string(),
+                        old,
+                        new,
+                        dest_path: Some(s.dest_path.clone()),
+                        config: s.config.clone(),
+                        hooks_path: s.hooks_path.clone(),
+                        pre_hook_done: false,
+                        package: Some(InstalledPackage {
+                            version: c.version.clone(),
+                            revision: c.revision,
+                            repo: c.repo.clone(),
+                            variant: s.variant,
+                            cid: s.cid.clone(),
+                            explicit: plan.explicit.contains(&c.name),
+                            files: s.files.clone(),
+                            provides: c.provides.clone(),
+                            dependencies: c.dependencies.iter().cloned().collect(),
+                            config: BTreeMap::new(),
+                        }),
+                    }
+                }
+            }
+        })
+        .collect();
+    let journal = Journal {
+        stage_path: stage_dir.path.clone(),
+        incoming,
+        steps,
+    };
+    journal.save(root_path);
+    // From here on the journal needs the builds until it is done.
+    stage_dir.keep = true;
+
+    let (done, failed) = apply(root_path, journal, installed);
+    for name in plan.explicit.iter() {
+        if let Some(p) = installed.packages.get_mut(name) {
+            p.explicit = true;
+        }
+    }
+    installed.save(root_path);
+    run_post_hooks(root_path, &done);
+    stage_dir.keep = false;
+    if let Some(failed) = failed {
+        panic!("{}", failed);
+    }
+}
+
+/// Finish the transaction an earlier run was cut short in, if any, and
+/// throw away builds no transaction needs. The caller holds the lock.
+pub fn finish_interrupted(root_path: &str, installed: &mut Installed) {
+    let journal = Journal::load(root_path);
+    let keep = journal.as_ref().map(|j| j.stage_path.clone());
+    if let Ok(entries) = std::fs::read_dir(format!("{}/usr/sps/tmp", root_path)) {
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if Some(&path) != keep.as_ref() && path.is_dir() {
+                std::fs::remove_dir_all(&path).unwrap();
+            }
+        }
+    }
+    let journal = match journal {
+        Some(journal) => journal,
+        None => return,
+    };
+    println!(
+        "Finishing a transaction that was cut short: {}.",
+        journal
+            .steps
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let mut stage_dir = StageDir {
+        path: journal.stage_path.clone(),
+        keep: true,
+    };
+    let (done, failed) = apply(root_path, journal, installed);
+    run_post_hooks(root_path, &done);
+    stage_dir.keep = false;
+    if let Some(failed) = failed {
+        panic!("{}", failed);
+    }
+}
+
+/// Carry out the steps of `journal`, recording each package in `installed`
+/// and dropping its step from the journal as soon as its files are in
+/// place. Returns the steps carried out, and what went wrong if a pre hook
+/// failed and the steps from it on were given up.
+fn apply(
+    root_path: &str,
+    mut journal: Journal,
+    installed: &mut Installed,
+) -> (Vec<Step>, Option<String>) {
+    let mut done: Vec<Step> = Vec::new();
+    while !journal.steps.is_empty() {
+        if !journal.steps[0].pre_hook_done {
+            let step = &journal.steps[0];
+            let hook = match step.package {
+                None => "sps_pre_remove.sh",
+                Some(_) => "sps_pre_install.sh",
+            };
+            if let Some(hooks_path) = step.hooks_path.as_ref() {
+                if !run_hook(root_path, hooks_path, hook, &step.name, &step.old, &step.new) {
+                    let left: Vec<&str> = journal.steps.iter().map(|s| s.name.as_str()).collect();
+                    let failed = if done.is_empty() {
+                        format!("{} of {} failed, nothing was changed", hook, step.name)
+                    } else {
+                        format!(
+                            "{} of {} failed. {} went through, {} did not.",
+                            hook,
+                            step.name,
+                            done.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", "),
+                            left.join(", ")
+                        )
+                    };
+                    Journal::clear(root_path);
+                    return (done, Some(failed));
+                }
+            }
+            journal.steps[0].pre_hook_done = true;
+            journal.save(root_path);
+        }
+        let step = &journal.steps[0];
+        match step.package.as_ref() {
+            None => {
+                if let Some(old) = installed.packages.remove(&step.name) {
+                    remove_files(root_path, &old.files, &journal.incoming, &old.config);
+                    crate::config_files::forget(root_path, &step.name, &old.config);
+                }
+            }
+            Some(package) => {
+                let dest_path = step.dest_path.as_ref().unwrap();
+                let mut old_config = BTreeMap::new();
+                if let Some(old) = installed.packages.get(&step.name) {
+                    remove_files(root_path, &old.files, &journal.incoming, &old.config);
+                    old_config = old.config.clone();
+                }
+                let (config, held) = crate::config_files::place(
+                    root_path,
+                    &step.name,
+                    dest_path,
+                    &step.config,
+                    &old_config,
+                );
+                let files: Vec<String> = package
+                    .files
+                    .iter()
+                    .filter(|f| !held.contains(f))
+                    .cloned()
+                    .collect();
+                copy_files(root_path, dest_path, &files);
+                keep_remove_hooks(root_path, &step.name, step.hooks_path.as_deref());
+                let mut package = package.clone();
+                package.config = config;
+                installed.packages.insert(step.name.clone(), package);
+            }
+        }
+        installed.save(root_path);
+        done.push(journal.steps.remove(0));
+        journal.save(root_path);
+    }
+    Journal::clear(root_path);
+    (done, None)
+}
+
+/// Run the post-install and post-remove hooks of the steps carried out.
+fn run_post_hooks(root_path: &str, done: &[Step]) {
+    for step in done.iter() {
+        let hook = match step.package {
+            None => "sps_post_remove.sh",
+            Some(_) => "sps_post_install.sh",
+        };
+        if let Some(hooks_path) = step.hooks_path.as_ref() {
+            if !run_hook(root_path, hooks_path, hook, &step.name, &step.old, &step.new) {
+                eprintln!("warning: {} of {} failed", hook, step.name);
+            }
+        }
+        if step.package.is_none() {
+            keep_remove_hooks(root_path, &step.name, None);
+        }
+    }
+}
+
+/// The package an action is about with the versions it goes from and to,
+/// empty for none.
+fn action_versions(action: &Action) -> (&str, String, String) {
+    let show = |(v, r): &(semver::Version, u64)| crate::index::display_version(v, *r);
+    let new = |c: &Candidate| crate::index::display_version(&c.version, c.revision);
+    match action {
+        Action::Install(c) => (&c.name, String::new(), new(c)),
+        Action::Upgrade(old, c) | Action::Downgrade(old, c) => (&c.name, show(old), new(c)),
+        Action::Remove(name, old) => (name, show(old), String::new()),
+    }
+}
+
+/// Where the remove hooks of an installed package are kept, since its
+/// build is gone by the time it is removed.
+fn installed_hooks_path(root_path: &str, name: &str) -> PathBuf {
+    PathBuf::from(format!("{}/usr/sps/hooks/{}", root_path, name))
+}
+
+/// Replace the kept remove hooks of `name` with the ones in `hooks_path`,
+/// or drop them.
+fn keep_remove_hooks(root_path: &str, name: &str, hooks_path: Option<&Path>) {
+    use std::fs::*;
+    let kept_path = installed_hooks_path(root_path, name);
+    if kept_path.is_dir() {
+        remove_dir_all(&kept_path).unwrap();
+    }
+    let hooks_path = match hooks_path {
+        Some(hooks_path) => hooks_path,
+        None => return,
+    };
+    for hook in ["sps_pre_remove.sh", "sps_post_remove.sh"].iter() {
+        let mut from = hooks_path.to_path_buf();
+        from.push(hook);
+        if from.is_file() {
+            create_dir_all(&kept_path).unwrap();
+            let mut to = kept_path.clone();
+            to.push(hook);
+            copy(&from, &to).unwrap();
+        }
+    }
+}
+
+/// Run `hook` from `hooks_path` if the package has it, with the versions
+/// the package goes from and to in SPS_OLD_VERSION and SPS_NEW_VERSION.
+/// Whether it succeeded.
+fn run_hook(
+    root_path: &str,
+    hooks_path: &Path,
+    hook: &str,
+    name: &str,
+    old: &str,
+    new: &str,
+) -> bool {
+    let mut hook_path = hooks_path.to_path_buf();
+    hook_path.push(hook);
+    if !hook_path.is_file() {
+        return true;
+    }
+    println!("Running {} of {}...", hook, name);
+    std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "cd {} && SPS_ROOT_DIR={} SPS_PACKAGE={} SPS_OLD_VERSION={} SPS_NEW_VERSION={} sh {}",
+            hooks_path.to_str().unwrap(),
+            root_path,
+            name,
+            old,
+            new,
+            hook
+        ))
+        .spawn()
+        .expect("failed to execute process")
+        .wait()
+        .unwrap()
+        .success()
+}
+
+/// The variant to install on `arch`, the one built for it if the package is
+/// built per arch. None if it is, but not for `arch`.
+pub fn pick_variant(c: &Candidate, arch: &str) -> Option<usize> {
+    let mut conf_path = c.path.clone();
+    conf_path.push("config.toml");
+    let build_ops = crate::repo::build_options(&crate::repo::read_project_config(&conf_path));
+    let per_arch = build_ops
+        .iter()
+        .any(|ops| ops.iter().any(|(key, _)| key == "archs"));
+    if !per_arch {
+        return Some(0);
+    }
+    build_ops
+        .iter()
+        .position(|ops| ops.iter().any(|(key, val)| key == "archs" && val == arch))
+}
+
+/// Fetch and build a variant into `<stage>/builds/<cid>`, unless a package
+/// split from the same variant already did. Installed files end up in its
+/// `dest` directory, the ones placed per subpackage in `split/<name>`.
+fn build(c: &Candidate, variant: usize, cid: &str, stage_path: &Path) -> PathBuf {
+    use std::fs::*;
+    let mut pkg_path = stage_path.to_path_buf();
+    pkg_path.push("builds");
+    pkg_path.push(cid);
+    if pkg_path.is_dir() {
+        return pkg_path;
+    }
+    create_dir_all(&pkg_path).unwrap();
+    crate::ipfs_get_and_uncompress(
+        &pkg_path,
+        &format!("/ipfs/{}", cid),
+        &format!("{}.tar", variant),
+    );
+    let mut tar_path = pkg_path.clone();
+    tar_path.push(format!("{}.tar", variant));
+    crate::un_tar(&tar_path);
+
+    let mut build_path = pkg_path.clone();
+    build_path.push(format!("{}", variant));
+    let mut dest_path = pkg_path.clone();
+    dest_path.push("dest");
+    create_dir_all(&dest_path).unwrap();
+    let mut split_path = pkg_path.clone();
+    split_path.push("split");
+    create_dir_all(&split_path).unwrap();
+    let exit_status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "cd {} && SPS_DEST_DIR={} SPS_SPLIT_DIR={} sh sps_build.sh",
+            build_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+            split_path.to_str().unwrap()
+        ))
+        .spawn()
+        .expect("failed to execute process")
+        .wait()
+        .unwrap();
+    assert!(
+        exit_status.success(),
+        "Building {} {} failed",
+        c.name,
+        c.version
+    );
+    pkg_path
+}
+
+/// Every file under `dir`, relative to it.
+fn files_in(dir: &Path) -> Vec<String> {
+    if !dir.is_dir() {
+        return Vec::new();
+    }
+    fs_extra::dir::get_dir_content(dir)
+        .unwrap()
+        .files
+        .iter()
+        .map(|f| {
+            Path::new(f)
+                .strip_prefix(dir)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect()
+}
+
+fn stage(c: &Candidate, stage_path: &Path) -> Staged {
+    use std::fs::*;
+    // resolve checked there is one.
+    let variant = pick_variant(c, std::env::consts::ARCH).unwrap();
+    let mut index_path = c.path.clone();
+    index_path.push("index");
+    let index = read_to_string(&index_path)
+        .unwrap()
+        .parse::<toml::Value>()
+        .unwrap();
+    let cid = index[&format!("{}", variant)]
+        .as_str()
+        .unwrap_or_else(|| panic!("{} {} has no variant {}", c.name, c.version, variant))
+        .to_string();
+
+    let build_path = build(c, variant, &cid, stage_path);
+    let mut dest_path = build_path.clone();
+    dest_path.push("dest");
+    let mut meta_path = c.path.clone();
+    meta_path.push("meta.toml");
+    let meta = read_to_string(&meta_path)
+        .unwrap()
+        .parse::<toml::Value>()
+        .unwrap();
+    let rules = |patterns: &toml::Value, what: &str| {
+        crate::repo::file_patterns(&build_path, patterns, what)
+    };
+    let matches = |rules: &ignore::gitignore::Gitignore, file: &str| {
+        rules.matched_path_or_any_parents(file, false).is_ignore()
+    };
+
+    let files = match meta.get("split_from") {
+        // A subpackage takes the files matching its patterns and the ones
+        // placed in its own directory.
+        Some(_) => {
+            let rules = rules(&meta["files"], "files");
+            let mut split_path = build_path.clone();
+            split_path.push("split");
+            split_path.push(&c.name);
+            let mut own_path = stage_path.to_path_buf();
+            own_path.push("split");
+            own_path.push(&c.name);
+            let matched: Vec<String> = files_in(&dest_path)
+                .into_iter()
+                .filter(|f| matches(&rules, f))
+                .collect();
+            for (from, files) in [(&dest_path, matched), (&split_path, files_in(&split_path))].iter() {
+                for file in files.iter() {
+                    let mut to = own_path.clone();
+                    to.push(file);
+                    create_dir_all(to.parent().unwrap()).unwrap();
+                    let mut from = from.to_path_buf();
+                    from.push(file);
+                    copy(&from, &to).unwrap();
+                }
+            }
+            dest_path = own_path;
+            files_in(&dest_path)
+        }
+        // The package they are split from keeps everything else.
+        None => {
+            let subpackages: Vec<_> = meta
+                .get("subpackage")
+                .and_then(|s| s.as_array())
+                .map(|s| s.iter().map(|s| rules(&s["files"], "files")).collect())
+                .unwrap_or_default();
+            files_in(&dest_path)
+                .into_iter()
+                .filter(|f| !subpackages.iter().any(|s| matches(s, f)))
+                .collect()
+        }
+    };
+    let config = match meta.get("config_files") {
+        Some(patterns) => {
+            let rules = rules(patterns, "config_files");
+            files.iter().filter(|f| matches(&rules, f)).cloned().collect()
+        }
+        None => Vec::new(),
+    };
+
+    // Hooks belong to the package the project is named after.
+    let hooks_path = if meta.get("split_from").is_some() {
+        None
+    } else {
+        let mut hooks_path = build_path.clone();
+        hooks_path.push(format!("{}", variant));
+        Some(hooks_path)
+    };
+    Staged {
+        variant,
+        cid,
+        dest_path,
+        files,
+        config,
+        hooks_path,
+    }
+}
+
+fn copy_files(root_path: &str, dest_path: &Path, files: &[String]) {
+    use std::fs::*;
+    for file in files.iter() {
+        let mut from = dest_path.to_path_buf();
+        from.push(file);
+        let to = PathBuf::from(format!("{}/{}", root_path, file));
+        create_dir_all(to.parent().unwrap()).unwrap();
+        copy(&from, &to).unwrap_or_else(|e| panic!("Could not install {:?}: {}", to, e));
+    }
+}
+
+/// Remove the installed `files` that are not also in `keep`. Config files
+/// edited since they were shipped with the digests in `config` stay.
+fn remove_files(
+    root_path: &str,
+    files: &[String],
+    keep: &[String],
+    config: &BTreeMap<String, String>,
+) {
+    for file in files.iter().filter(|f| !keep.contains(f)) {
+        if config
+            .get(file)
+            .is_some_and(|d| crate::config_files::is_modified(root_path, file, d))
+        {
+            println!("Kept {}, it was edited.", file);
+            continue;
+        }
+        let path = PathBuf::from(format!("{}/{}", root_path, file));
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                panic!("Could not remove {:?}: {}", path, e);
+            }
+        }
+    }
+}